@@ -1,5 +1,17 @@
-use clap::{arg, command, ArgAction, ArgGroup, Args, Command, Parser, Subcommand, ValueEnum};
-use std::path::PathBuf;
+use clap::{
+    arg, command, ArgAction, ArgGroup, Args, Command, CommandFactory, Parser, Subcommand, ValueEnum,
+};
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command as ShellCommand};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // Define value enums for use in arguments
 #[derive(ValueEnum, Debug, Clone)]
@@ -10,6 +22,88 @@ enum LogLevel {
     Error,
 }
 
+#[derive(ValueEnum, Debug, Clone)]
+enum LogFormat {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum LogOrder {
+    Realtime,
+    File,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum PathStyle {
+    Native,
+    Slash,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum GlobRoot {
+    Source,
+    Cwd,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum OnInvalid {
+    Replace,
+    Error,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum DedupLines {
+    Global,
+    PerFile,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum Schedule {
+    Size,
+    Order,
+    Steal,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum OnMissingInput {
+    Skip,
+    Error,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum ConfigFormat {
+    Text,
+    Json,
+    Shell,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum InputOrder {
+    AsGiven,
+    Sorted,
+    Random,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum Decode {
+    Base64,
+    Hex,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum OutputDirLayout {
+    Flat,
+    Mirror,
+    Hash,
+}
+
 #[derive(ValueEnum, Debug, Clone)]
 enum OutputFormat {
     Json,
@@ -17,6 +111,46 @@ enum OutputFormat {
     Text,
 }
 
+// Input sampling: a fraction of the inputs or an exact count
+#[derive(Debug, Clone)]
+enum Sample {
+    Fraction(f64),
+    Count(usize),
+}
+
+// Batch sizing: a fixed number of files or sized from measured throughput
+#[derive(Debug, Clone)]
+enum BatchSize {
+    Fixed(usize),
+    Auto,
+}
+
+// Recursion depth: a default plus per-source overrides
+#[derive(Debug, Clone)]
+struct MaxDepth {
+    default: u32,
+    sources: Vec<(PathBuf, u32)>,
+}
+
+impl MaxDepth {
+    fn for_source(&self, source: &Path) -> u32 {
+        self.sources
+            .iter()
+            .find(|(path, _)| path == source)
+            .map_or(self.default, |(_, depth)| *depth)
+    }
+}
+
+impl std::fmt::Display for MaxDepth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.default)?;
+        for (path, depth) in &self.sources {
+            write!(f, ", {}: {}", path.display(), depth)?;
+        }
+        Ok(())
+    }
+}
+
 // Define the main CLI structure
 #[derive(Parser, Debug)]
 #[command(
@@ -32,11 +166,56 @@ struct Cli {
         short,
         long,
         value_enum,
+        env = "MYCLI_LOG_LEVEL",
         help = "Set the logging level",
         default_value = "info"
     )]
     log_level: LogLevel,
 
+    #[arg(
+        global = true,
+        long,
+        value_enum,
+        help = "Set the log output format (json emits one object per event on stderr)",
+        default_value = "human"
+    )]
+    log_format: LogFormat,
+
+    #[arg(
+        global = true,
+        long,
+        value_enum,
+        help = "Emit worker logs as they happen or grouped per file once it completes",
+        default_value = "realtime"
+    )]
+    log_order: LogOrder,
+
+    #[arg(
+        global = true,
+        long,
+        value_name = "PATH",
+        help = "Also write log events to this file"
+    )]
+    log_file: Option<PathBuf>,
+
+    #[arg(
+        global = true,
+        long,
+        value_enum,
+        requires = "log_file",
+        help = "Set the logging level for the log file (defaults to --log-level)"
+    )]
+    log_file_level: Option<LogLevel>,
+
+    #[arg(
+        global = true,
+        long,
+        requires = "log_file",
+        help = "Append to the log file instead of truncating it",
+        action = ArgAction::SetTrue
+    )]
+    log_file_append: bool,
+
     #[arg(
         global = true,
         short,
@@ -46,12 +225,120 @@ struct Cli {
     )]
     verbose: bool,
 
+    #[arg(
+        global = true,
+        long,
+        help = "Flush stdout after each record instead of block buffering",
+        action = ArgAction::SetTrue
+    )]
+    line_buffered: bool,
+
+    #[arg(
+        global = true,
+        long,
+        help = "Exit with an error if any warning was emitted",
+        action = ArgAction::SetTrue
+    )]
+    fail_on_warning: bool,
+
+    #[arg(
+        global = true,
+        long,
+        value_name = "FILE",
+        help = "Load KEY=VALUE lines from a dotenv file for MYCLI_* resolution"
+    )]
+    config_env_file: Option<PathBuf>,
+
+    #[arg(
+        global = true,
+        long,
+        value_name = "NAME",
+        env = "MYCLI_PROFILE",
+        help = "Config profile to use: the profiles.<NAME> section of the config file"
+    )]
+    profile: Option<String>,
+
+    #[arg(
+        global = true,
+        long,
+        help = "List each skipped file in the output",
+        action = ArgAction::SetTrue
+    )]
+    show_skipped: bool,
+
+    #[arg(
+        global = true,
+        long,
+        help = "Suppress per-file error lines while still counting them",
+        action = ArgAction::SetTrue
+    )]
+    quiet_errors: bool,
+
+    #[arg(
+        global = true,
+        long,
+        value_enum,
+        help = "Print paths with native separators or always with forward slashes",
+        default_value = "native"
+    )]
+    path_style: PathStyle,
+
+    #[arg(
+        global = true,
+        long,
+        help = "Indent JSON output instead of one compact record per line",
+        action = ArgAction::SetTrue
+    )]
+    json_pretty: bool,
+
+    #[arg(
+        global = true,
+        long,
+        help = "Print a final JSON status object (status, exit code, processed and failed counts) to stderr when the run ends",
+        action = ArgAction::SetTrue
+    )]
+    status_json: bool,
+
+    #[arg(
+        global = true,
+        long,
+        help = "Refuse any change to the config file",
+        action = ArgAction::SetTrue
+    )]
+    config_readonly: bool,
+
+    #[arg(
+        global = true,
+        long,
+        value_name = "FILE",
+        help = "Hold an exclusive lock on this file for the whole run"
+    )]
+    lock: Option<PathBuf>,
+
+    #[arg(
+        global = true,
+        long,
+        value_name = "SECS",
+        requires = "lock",
+        help = "Wait up to this long for the lock instead of failing immediately"
+    )]
+    lock_wait: Option<u64>,
+
+    #[arg(
+        global = true,
+        long,
+        help = "Read piped input from stdin even when it is a terminal",
+        action = ArgAction::SetTrue
+    )]
+    force_stdin: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 // Define subcommands
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Manage files and directories
     Files(FileArgs),
@@ -65,10 +352,57 @@ enum Commands {
 
 // Arguments for the Files subcommand
 #[derive(Args, Debug)]
+#[command(
+    group(
+        ArgGroup::new("sources")
+            .required(true)
+            .args(["source", "stdin0"]),
+    ),
+    group(
+        ArgGroup::new("mirror")
+            .multiple(true)
+            .args(["stdin0", "preserve_structure"]),
+    ),
+)]
 struct FileArgs {
-    /// Source path for file operations
-    #[arg(short, long, value_name = "PATH")]
-    source: PathBuf,
+    /// Source paths for file operations (can specify multiple)
+    #[arg(short, long, value_name = "PATH", num_args = 1..)]
+    source: Vec<PathBuf>,
+
+    /// Read NUL-separated source paths from stdin (e.g. from find -print0)
+    #[arg(long, requires = "destination", action = ArgAction::SetTrue)]
+    stdin0: bool,
+
+    /// Reproduce each source's path relative to the common base under the destination
+    ///
+    /// Without this, sources are copied flat into the destination by file name,
+    /// and two sources with the same file name are refused.
+    #[arg(long, requires = "destination", action = ArgAction::SetTrue)]
+    preserve_structure: bool,
+
+    /// Place each source at a path computed from it, e.g. '{ext}/{name}'
+    ///
+    /// Fields: name, stem, ext, parent (the name of the containing
+    /// directory), and date, year, month and day from the source's mtime in
+    /// UTC. The result is taken relative to the destination and may not use
+    /// `..` to leave it; empty path segments are dropped. The `{newline}` and
+    /// `{tab}` escapes are not allowed, and the run is refused if two sources
+    /// would land on the same path.
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        requires = "destination",
+        conflicts_with_all = ["preserve_structure", "stdin0"],
+        value_parser = parse_destination_template
+    )]
+    destination_template: Option<String>,
+
+    /// Directory stripped from sources to get their place under the destination
+    ///
+    /// Used by --stdin0 and --preserve-structure. With --preserve-structure it
+    /// defaults to the deepest directory containing every source.
+    #[arg(long, value_name = "DIR", requires = "mirror")]
+    base: Option<PathBuf>,
 
     /// Destination path for file operations
     #[arg(short, long, value_name = "PATH")]
@@ -79,12 +413,99 @@ struct FileArgs {
     recursive: bool,
 
     /// File patterns to include (can specify multiple)
+    ///
+    /// `*` matches within a single path component and never crosses a
+    /// directory separator, while `**` matches any number of directories.
+    /// So `*.txt` only matches files directly under the glob root and
+    /// `**/*.txt` matches at any depth.
+    ///
+    /// A pattern starting with `!` excludes what it matches. Patterns are
+    /// applied in order and the last one that matches a path wins; if every
+    /// pattern is a negation, everything they don't exclude is included.
     #[arg(short, long, value_name = "PATTERN", num_args = 1..)]
     patterns: Option<Vec<String>>,
 
-    /// Maximum depth for recursive operations
-    #[arg(long, value_name = "NUM", default_value = "10")]
-    max_depth: u32,
+    /// Directory that relative patterns are anchored at
+    #[arg(long, value_enum, default_value = "source")]
+    glob_root: GlobRoot,
+
+    /// Maximum depth for recursive operations, optionally per source
+    ///
+    /// Either a plain number or a comma-separated list such as
+    /// `3,src1:2,src2:5`, where `PATH:NUM` entries override the depth for the
+    /// source given exactly as PATH and a bare number sets the default for
+    /// the rest (10 if omitted).
+    #[arg(long, value_name = "SPEC", default_value = "10", value_parser = parse_max_depth)]
+    max_depth: MaxDepth,
+
+    /// Create missing destination parent directories (implied by --recursive)
+    #[arg(long, visible_alias = "mkdir", action = ArgAction::SetTrue)]
+    parents: bool,
+
+    /// Merge into an existing destination tree instead of requiring an empty one
+    #[arg(long, action = ArgAction::SetTrue)]
+    merge: bool,
+
+    /// Allow copying into a non-empty destination without --merge
+    #[arg(long, action = ArgAction::SetTrue)]
+    force: bool,
+
+    /// Skip destination files whose mtime is not older than the source
+    #[arg(long, visible_alias = "only-newer", action = ArgAction::SetTrue)]
+    update: bool,
+
+    /// Abort once this many files have failed
+    #[arg(long, value_name = "N")]
+    max_errors: Option<usize>,
+
+    /// Write through symlinks found in the destination instead of replacing them
+    ///
+    /// By default a symlink at a target path is replaced by the copied file,
+    /// and the run is refused if a directory symlink under the destination
+    /// would lead a copy outside the destination root.
+    #[arg(long, action = ArgAction::SetTrue)]
+    keep_dest_symlinks: bool,
+
+    /// Ask before replacing each existing destination file
+    ///
+    /// Files only plans copies, so no prompt is shown yet. Stdin must still
+    /// be a terminal, since that is where the answers will be read from.
+    #[arg(short, long, conflicts_with = "stdin0", action = ArgAction::SetTrue)]
+    interactive: bool,
+
+    /// Skip sources whose content already exists somewhere in the destination
+    #[arg(long, action = ArgAction::SetTrue)]
+    dedupe_destination: bool,
+
+    /// Copy extended attributes and POSIX ACLs to the destination
+    ///
+    /// Supported on Linux and macOS, and ACLs only on Linux; elsewhere a
+    /// warning is printed. Files only plans copies, so nothing is copied yet.
+    #[arg(long, action = ArgAction::SetTrue)]
+    preserve_xattr: bool,
+
+    /// Give each copy the uid and gid of its source
+    ///
+    /// Needs enough privilege to chown, which usually means running as root.
+    /// Files only plans copies, so ownership is not checked or changed yet.
+    /// This is a no-op on Windows.
+    #[arg(long, action = ArgAction::SetTrue)]
+    preserve_ownership: bool,
+
+    /// Per-record output template, e.g. '{path} {size} {status}'
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_template)]
+    template: Option<String>,
+
+    /// Read the --template from FILE; it may span several lines
+    ///
+    /// The fields are checked when the file is loaded. A single trailing
+    /// newline, as most editors add, is dropped.
+    #[arg(long, value_name = "FILE", conflicts_with = "template", value_parser = parse_template_file)]
+    template_file: Option<String>,
+
+    /// Canonicalize source paths and drop duplicates
+    #[arg(long, action = ArgAction::SetTrue)]
+    normalize: bool,
 }
 
 // Arguments for the Config subcommand
@@ -92,101 +513,2658 @@ struct FileArgs {
 #[command(group(
     ArgGroup::new("config_action")
         .required(true)
-        .args(["set", "get", "list"]),
+        .args(["set", "set_file", "get", "list", "history", "undo", "verify", "verify_glob", "lock_keys"]),
 ))]
 struct ConfigArgs {
     /// Set a configuration value
     #[arg(short, long, value_names = ["KEY", "VALUE"], num_args = 2)]
     set: Option<Vec<String>>,
 
-    /// Get a configuration value
-    #[arg(short, long, value_name = "KEY")]
-    get: Option<String>,
+    /// Set every `key=value` or `key: value` line of FILE in one write
+    ///
+    /// Blank lines and `#` comments are skipped. The whole file is checked
+    /// first, so if any line is invalid nothing is written.
+    #[arg(long, value_name = "FILE")]
+    set_file: Option<PathBuf>,
+
+    /// Get one or more configuration values
+    #[arg(short, long, value_name = "KEY", num_args = 1..)]
+    get: Option<Vec<String>>,
+
+    /// Fail if any requested key is missing instead of just reporting it
+    #[arg(long, requires = "get", action = ArgAction::SetTrue)]
+    all_required: bool,
+
+    /// Decode the value of a single --get key and write the raw bytes to stdout
+    ///
+    /// This bypasses the normal formatted output: nothing but the decoded
+    /// bytes is written, without a trailing newline. A value that is not
+    /// valid in the chosen encoding is an error.
+    #[arg(long, value_enum, requires = "get", conflicts_with = "format")]
+    decode: Option<Decode>,
 
     /// List all configuration values
-    #[arg(short, long, action = ArgAction::SetTrue)]
+    #[arg(long, action = ArgAction::SetTrue)]
     list: bool,
 
+    /// List recent backups of the configuration file
+    #[arg(long, action = ArgAction::SetTrue)]
+    history: bool,
+
+    /// Restore the most recent backup, keeping the current file as a backup
+    #[arg(long, action = ArgAction::SetTrue)]
+    undo: bool,
+
+    /// Check that the configuration file can be loaded, without changing it
+    #[arg(long, action = ArgAction::SetTrue)]
+    verify: bool,
+
+    /// Check every configuration file matching a glob, e.g. 'configs/**/*.yaml'
+    ///
+    /// Files are checked in parallel but reported sorted by path, and the
+    /// command exits non-zero if any of them fails.
+    #[arg(long, value_name = "PATTERN")]
+    verify_glob: Option<String>,
+
+    /// Record the config file's current key structure in its lock file
+    ///
+    /// Only mapping keys outside lists are locked; list items and the
+    /// contents of block scalars are not part of the structure.
+    #[arg(long, action = ArgAction::SetTrue)]
+    lock_keys: bool,
+
+    /// Refuse to run if the config's keys differ from those in the lock file
+    ///
+    /// Only the set of keys is compared, so values can still change. Record
+    /// the structure first with --lock-keys.
+    #[arg(long, conflicts_with = "lock_keys", action = ArgAction::SetTrue)]
+    check_lock: bool,
+
+    /// Lock file used by --lock-keys and --check-lock [default: FILE.lock]
+    #[arg(long, value_name = "PATH")]
+    lock_file: Option<PathBuf>,
+
+    /// Number of files --verify-glob checks at once
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "1",
+        requires = "verify_glob"
+    )]
+    threads: usize,
+
+    /// Number of backup generations to keep
+    #[arg(long, value_name = "NUM", default_value = "5")]
+    keep: usize,
+
+    /// Only set the key if it is not already present
+    ///
+    /// When the condition fails the command exits 0 without writing. With
+    /// --dry-run the condition is still checked and reported.
+    #[arg(long, requires = "set", conflicts_with = "if_exists", action = ArgAction::SetTrue)]
+    if_not_exists: bool,
+
+    /// Only set the key if it is already present
+    ///
+    /// When the condition fails the command exits 0 without writing. With
+    /// --dry-run the condition is still checked and reported.
+    #[arg(long, requires = "set", action = ArgAction::SetTrue)]
+    if_exists: bool,
+
+    /// Run CMD on the candidate document and only write it if CMD exits 0
+    #[arg(long, value_name = "CMD", requires = "set")]
+    validate_cmd: Option<String>,
+
+    /// Output format for --get (shell shows each key's shell variable name)
+    #[arg(long, value_enum, default_value = "text")]
+    format: ConfigFormat,
+
+    /// Show what a change would do without writing the file
+    #[arg(long, action = ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// With --list, only show keys whose values differ from the built-in defaults
+    ///
+    /// There are no built-in defaults to compare against yet, so this only
+    /// changes what --list reports it is doing.
+    #[arg(long, requires = "list", action = ArgAction::SetTrue)]
+    diff_from_defaults: bool,
+
+    /// Character that splits nested keys into segments
+    ///
+    /// A separator inside a segment can also be escaped with a backslash, so
+    /// `servers.db\.prod\.internal.port` addresses the `db.prod.internal`
+    /// entry under `servers`. A literal backslash is written `\\`.
+    #[arg(long, value_name = "CHAR", default_value = ".")]
+    key_separator: char,
+
+    /// Reject keys that are not known to the tool
+    ///
+    /// There is no registry of known keys yet, so this is only reported.
+    #[arg(long, action = ArgAction::SetTrue)]
+    strict: bool,
+
     /// Configuration file to use
-    #[arg(short, long, value_name = "FILE", default_value = "config.yaml")]
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        env = "MYCLI_CONFIG_FILE",
+        default_value = "config.yaml"
+    )]
     file: PathBuf,
 }
 
 // Arguments for the Process subcommand
 #[derive(Args, Debug)]
+#[command(group(
+    ArgGroup::new("inputs")
+        .required(true)
+        .multiple(true)
+        .args(["input_files", "input_list", "input_glob", "files_from_cmd", "apply_plan"]),
+))]
 struct ProcessArgs {
-    /// Input files to process
-    #[arg(required = true, num_args = 1.., value_name = "FILES")]
+    /// Input files to process (`-` reads from stdin)
+    #[arg(num_args = 1.., value_name = "FILES")]
     input_files: Vec<PathBuf>,
 
-    /// Output format
-    #[arg(short, long, value_enum, default_value = "text")]
-    format: OutputFormat,
+    /// Read additional input paths from a file, one per line
+    #[arg(long, value_name = "FILE")]
+    input_list: Option<PathBuf>,
 
-    /// Number of threads to use
-    #[arg(short, long, value_name = "NUM", default_value = "1")]
-    threads: u32,
+    /// Add the files matching a glob, e.g. 'data/**/*.csv'
+    #[arg(long, value_name = "PATTERN")]
+    input_glob: Option<String>,
 
-    /// Batch size for processing
-    #[arg(short, long, value_name = "SIZE", default_value = "100")]
-    batch_size: usize,
+    /// Add the paths printed by a shell command, one per line
+    ///
+    /// CMD is run through the shell with the same privileges as this tool, so
+    /// only pass commands you trust. A non-zero exit aborts before processing.
+    #[arg(long, value_name = "CMD")]
+    files_from_cmd: Option<String>,
 
-    /// Enable dry run mode
-    #[arg(long, action = ArgAction::SetTrue)]
-    dry_run: bool,
+    /// Split --files-from-cmd output on NUL bytes instead of newlines
+    #[arg(long, requires = "files_from_cmd", action = ArgAction::SetTrue)]
+    null: bool,
 
-    /// Custom options (key=value pairs)
-    #[arg(short, long, value_name = "KEY=VALUE", value_parser = parse_key_val)]
-    options: Option<Vec<(String, String)>>,
-}
+    /// Allow --input-glob to match nothing
+    #[arg(long, requires = "input_glob", action = ArgAction::SetTrue)]
+    allow_empty: bool,
 
-// Helper function to parse key-value pairs
-fn parse_key_val(s: &str) -> Result<(String, String), String> {
-    let pos = s
-        .find('=')
-        .ok_or_else(|| format!("invalid KEY=value: no `=` found in `{s}`"))?;
-    Ok((s[..pos].to_string(), s[pos + 1..].to_string()))
-}
+    /// What to do when an input path does not exist
+    #[arg(long, value_enum, default_value = "error")]
+    on_missing_input: OnMissingInput,
 
-fn main() {
-    let cli = Cli::parse();
+    /// Expand directories in the input list into the files they contain
+    ///
+    /// Symlinked directories found while walking are skipped.
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    recursive: bool,
 
-    // Set up logging based on global options
-    println!("Log level set to: {:?}", cli.log_level);
-    if cli.verbose {
-        println!("Verbose mode enabled");
-    }
+    /// Treat .tar and .zip inputs as their member files, named `archive!member`
+    ///
+    /// --patterns are matched against member names, and do not filter out
+    /// the archives themselves when --recursive finds them. Members are
+    /// listed without extracting anything to disk. Compressed tarballs (.tar.gz,
+    /// .tgz) cannot be listed yet and are kept as a single input with a
+    /// warning.
+    #[arg(long, action = ArgAction::SetTrue)]
+    expand_archives: bool,
 
-    // Handle commands
+    /// Patterns selecting files found by --recursive (same syntax as Files)
+    ///
+    /// Matched against the path relative to the expanded directory. A
+    /// pattern starting with `!` excludes what it matches, the last matching
+    /// pattern wins, and a list of only negations includes everything else.
+    /// Files named directly on the command line are never filtered.
+    #[arg(short, long, value_name = "PATTERN", num_args = 1..)]
+    patterns: Option<Vec<String>>,
+
+    /// Maximum depth for recursive expansion, optionally per input directory
+    ///
+    /// Takes the same `3,src1:2,src2:5` form as `files --max-depth`.
+    #[arg(long, value_name = "SPEC", default_value = "10", value_parser = parse_max_depth)]
+    max_depth: MaxDepth,
+
+    /// Output format (inferred from the --output extension, else text)
+    #[arg(short, long, value_enum, env = "MYCLI_FORMAT")]
+    format: Option<OutputFormat>,
+
+    /// Also stream results as NDJSON to this Unix domain socket
+    #[arg(long, value_name = "PATH")]
+    result_socket: Option<PathBuf>,
+
+    /// Emit YAML as a stream of `---` separated documents, one per record
+    #[arg(long, action = ArgAction::SetTrue)]
+    yaml_stream: bool,
+
+    /// Write results to this file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Compress the combined, ordered output stream
+    #[arg(long, value_enum)]
+    compress_output: Option<Compression>,
+
+    /// Append to an existing --output file instead of truncating it
+    #[arg(long, requires = "output", action = ArgAction::SetTrue)]
+    output_append: bool,
+
+    /// Write each input's result to its own file under DIR
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    output_split: Option<PathBuf>,
+
+    /// How --output-split arranges files under DIR
+    ///
+    /// flat writes every result directly into DIR, adding a `-N` suffix when
+    /// two inputs share a file name. mirror reproduces each input's path, so
+    /// names cannot collide. hash shards results into subdirectories named by
+    /// a hash prefix of the input path, with flat's suffixing inside a shard.
+    #[arg(long, value_enum, default_value = "mirror", requires = "output_split")]
+    output_dir_layout: OutputDirLayout,
+
+    /// Extension for files written by --output-split (defaults to the format's)
+    #[arg(long, value_name = "EXT", requires = "output_split")]
+    output_ext: Option<String>,
+
+    /// Permissions for files created by --output and --output-split, e.g. 0640
+    ///
+    /// Applied on Unix only; elsewhere a warning is printed and new files get
+    /// the platform's default permissions.
+    #[arg(long, value_name = "OCTAL", value_parser = parse_mode)]
+    output_mode: Option<u32>,
+
+    /// Text encoding of input files
+    #[arg(long, value_name = "LABEL", default_value = "utf-8")]
+    input_encoding: String,
+
+    /// Text encoding for output
+    #[arg(long, value_name = "LABEL", default_value = "utf-8")]
+    output_encoding: String,
+
+    /// How to handle byte sequences invalid in the input encoding
+    #[arg(long, value_enum, default_value = "replace")]
+    on_invalid: OnInvalid,
+
+    /// Remove ANSI escape sequences (colors, cursor movement) from each line
+    #[arg(long, action = ArgAction::SetTrue)]
+    strip_ansi: bool,
+
+    /// Strip leading and trailing whitespace from each line
+    #[arg(long, action = ArgAction::SetTrue)]
+    trim: bool,
+
+    /// Remove this prefix from each line that starts with it
+    #[arg(long, value_name = "S")]
+    strip_prefix: Option<String>,
+
+    /// Remove this suffix from each line that ends with it
+    #[arg(long, value_name = "S")]
+    strip_suffix: Option<String>,
+
+    /// Lowercase each line
+    #[arg(long, conflicts_with = "to_upper", action = ArgAction::SetTrue)]
+    to_lower: bool,
+
+    /// Uppercase each line
+    #[arg(long, action = ArgAction::SetTrue)]
+    to_upper: bool,
+
+    /// Suppress repeated lines across all inputs or within each file
+    #[arg(long, value_enum)]
+    dedup_lines: Option<DedupLines>,
+
+    /// Memory for the dedup set before falling back to an external sort
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "256MiB")]
+    dedup_max_mem: u64,
+
+    /// Pipe each input through CMD and use its stdout as the result
+    #[arg(long, value_name = "CMD")]
+    transform_cmd: Option<String>,
+
+    /// Run --transform-cmd once per record instead of once per file
+    #[arg(long, requires = "transform_cmd", action = ArgAction::SetTrue)]
+    per_record: bool,
+
+    /// Rewrite each input in place, replacing literal FROM with TO
+    #[arg(long, value_names = ["FROM", "TO"], num_args = 2)]
+    replace: Option<Vec<String>>,
+
+    /// Rewrite each input in place, replacing regex matches of PATTERN with TO
+    #[arg(
+        long,
+        value_names = ["PATTERN", "TO"],
+        num_args = 2,
+        conflicts_with = "replace"
+    )]
+    replace_regex: Option<Vec<String>>,
+
+    /// Keep the original of each rewritten file with this suffix
+    #[arg(long, value_name = "SUFFIX")]
+    backup_suffix: Option<String>,
+
+    /// Memory-map inputs at least --mmap-threshold in size instead of buffering reads
+    #[arg(long, action = ArgAction::SetTrue)]
+    mmap: bool,
+
+    /// Smallest input size to memory-map
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "16MiB")]
+    mmap_threshold: u64,
+
+    /// Maximum number of files open at once across all workers
+    #[arg(long, value_name = "NUM")]
+    max_open_files: Option<usize>,
+
+    /// Number of threads to use
+    #[arg(
+        short,
+        long,
+        value_name = "NUM",
+        env = "MYCLI_THREADS",
+        default_value = "1"
+    )]
+    threads: u32,
+
+    /// How inputs are distributed across workers
+    #[arg(long, value_enum, default_value = "steal")]
+    schedule: Schedule,
+
+    /// Scale down active workers while available memory is below --min-free-memory
+    #[arg(long, action = ArgAction::SetTrue)]
+    adaptive_threads: bool,
+
+    /// Available memory below which --adaptive-threads sheds workers
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        default_value = "512MiB",
+        requires = "adaptive_threads"
+    )]
+    min_free_memory: u64,
+
+    /// Pin each worker thread to a distinct CPU core
+    ///
+    /// Mostly helps CPU-bound, cache-sensitive processing. Ignored with a
+    /// warning on platforms without affinity support.
+    #[arg(long, action = ArgAction::SetTrue)]
+    pin_threads: bool,
+
+    /// Batch size for processing, or `auto` to size batches from throughput
+    ///
+    /// With `auto`, batches grow while files are cheap and shrink when they
+    /// get expensive, aiming for --batch-target-ms per batch. The chosen
+    /// sizes are logged at debug level.
+    #[arg(
+        short,
+        long,
+        value_name = "SIZE",
+        env = "MYCLI_BATCH_SIZE",
+        default_value = "100",
+        value_parser = parse_batch_size
+    )]
+    batch_size: BatchSize,
+
+    /// Target duration of one batch under --batch-size auto [default: 250]
+    #[arg(long, value_name = "MS")]
+    batch_target_ms: Option<u64>,
+
+    /// Enable dry run mode
+    ///
+    /// Also prints a rough duration estimate, scaled from how fast a small
+    /// sample of the inputs can be read. Writes are not timed, so the real
+    /// run can be slower.
+    #[arg(long, action = ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// With --dry-run, save the resolved inputs to FILE as a plan
+    ///
+    /// Each input is recorded with its size and modification time so that
+    /// --apply-plan can tell whether it changed in the meantime. Archive
+    /// members have no stamp of their own, so this cannot be combined with
+    /// --expand-archives.
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "dry_run",
+        conflicts_with = "expand_archives"
+    )]
+    plan_file: Option<PathBuf>,
+
+    /// Process exactly the inputs of a plan saved with --plan-file
+    ///
+    /// The run is refused if any planned input is gone or its size or
+    /// modification time no longer matches the plan. The plan already holds
+    /// the selected inputs in order, so it cannot be combined with other
+    /// input sources or with options that select or reorder inputs.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "dry_run", "input_files", "input_list", "input_glob", "files_from_cmd",
+            "recursive", "expand_archives", "normalize", "min_size", "max_size",
+            "sample", "input_order",
+        ]
+    )]
+    apply_plan: Option<PathBuf>,
+
+    /// Custom options (key=value pairs)
+    #[arg(short, long, value_name = "KEY=VALUE", value_parser = parse_key_val)]
+    options: Option<Vec<(String, String)>>,
+
+    /// Per-record output template, e.g. '{path} {size} {status}'
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_template)]
+    template: Option<String>,
+
+    /// Read the --template from FILE; it may span several lines
+    ///
+    /// The fields are checked when the file is loaded. A single trailing
+    /// newline, as most editors add, is dropped.
+    #[arg(long, value_name = "FILE", conflicts_with = "template", value_parser = parse_template_file)]
+    template_file: Option<String>,
+
+    /// Run CMD once after the batch, with MYCLI_PROCESSED, MYCLI_FAILED and MYCLI_EXIT set
+    ///
+    /// CMD runs through the shell when Process ends, whether it succeeded or
+    /// not, before any --status-json line. Its own exit status only produces
+    /// a warning.
+    #[arg(long, value_name = "CMD")]
+    then: Option<String>,
+
+    /// Only run --then when the batch succeeded
+    #[arg(long, requires = "then", action = ArgAction::SetTrue)]
+    then_on_success: bool,
+
+    /// Abort once this many files have failed
+    #[arg(long, value_name = "N")]
+    max_errors: Option<usize>,
+
+    /// Checkpoint file recording completed inputs so a rerun can resume
+    #[arg(long, visible_alias = "resume", value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// Directory for intermediate files (defaults to the system temp dir)
+    #[arg(long, value_name = "DIR")]
+    tmp_dir: Option<PathBuf>,
+
+    /// Keep intermediate files instead of removing them
+    #[arg(long, action = ArgAction::SetTrue)]
+    keep_temp: bool,
+
+    /// Skip inputs smaller than this size
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Skip inputs larger than this size
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Process a random subset of inputs: a fraction (0.1) or a count (50)
+    ///
+    /// Sampling happens after --min-size/--max-size, so a count picks from the
+    /// inputs that passed the size filters.
+    #[arg(long, value_name = "FRACTION_OR_COUNT", value_parser = parse_sample)]
+    sample: Option<Sample>,
+
+    /// Seed for reproducible sampling and --input-order random (random when not given)
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+
+    /// Order in which inputs are handed to the workers
+    ///
+    /// This only changes which input is processed first, not how results are
+    /// written. `random` shuffles with --seed, which helps load testing.
+    /// With --checkpoint, prefer `sorted` so a resumed run walks the inputs
+    /// in the same order as the interrupted one.
+    #[arg(long, value_enum, default_value = "as-given")]
+    input_order: InputOrder,
+
+    /// Canonicalize input paths and drop duplicates
+    #[arg(long, action = ArgAction::SetTrue)]
+    normalize: bool,
+}
+
+// Number of warnings emitted during this run
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+// Number of items handled, and how many of them failed, for --status-json
+static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+// The --then command and whether it only runs on success, set by Process
+static THEN: OnceLock<(String, bool)> = OnceLock::new();
+
+// Logging options that warn() follows, set once the command line is parsed
+struct Logger {
+    json: bool,
+    // The --log-file, when its level lets warnings through
+    file: Option<Mutex<File>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+// Helper function to format one log event as human text or a JSON object
+fn log_line(level: &str, message: &str, json: bool) -> String {
+    if json {
+        format!(
+            "{{\"level\":{},\"message\":{}}}",
+            json_string(level),
+            json_string(message)
+        )
+    } else {
+        let mut label = level.to_string();
+        label[..1].make_ascii_uppercase();
+        format!("{label}: {message}")
+    }
+}
+
+// Helper function to report a warning on stderr and count it
+fn warn(message: &str) {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+    let json = LOGGER.get().is_some_and(|logger| logger.json);
+    let line = log_line("warning", message, json);
+    eprintln!("{line}");
+    if let Some(file) = LOGGER.get().and_then(|logger| logger.file.as_ref()) {
+        let _ = writeln!(file.lock().unwrap(), "{line}");
+    }
+}
+
+// Helper function to count a failed item, ending the run once --max-errors is reached
+fn record_failure(max_errors: Option<usize>, status_json: bool) {
+    let failed = FAILED.fetch_add(1, Ordering::Relaxed) + 1;
+    if let Some(limit) = max_errors.filter(|limit| failed >= *limit) {
+        eprintln!("Error: max errors reached ({failed} failed, limit {limit})");
+        finish(1, status_json);
+    }
+}
+
+// Helper function to run the --then command with the run's counts in its
+// environment. Returns whether it was run.
+fn run_then(cmd: &str, on_success: bool, exit_code: i32) -> bool {
+    if on_success && exit_code != 0 {
+        return false;
+    }
+    let status = shell_command(cmd)
+        .env(
+            "MYCLI_PROCESSED",
+            PROCESSED.load(Ordering::Relaxed).to_string(),
+        )
+        .env("MYCLI_FAILED", FAILED.load(Ordering::Relaxed).to_string())
+        .env("MYCLI_EXIT", exit_code.to_string())
+        .status();
+    match status {
+        Ok(status) if !status.success() => warn(&format!("--then `{cmd}` failed with {status}")),
+        Ok(_) => {}
+        Err(err) => warn(&format!("cannot run --then `{cmd}`: {err}")),
+    }
+    true
+}
+
+// Helper function to end the run, printing the status line last if requested
+fn finish(exit_code: i32, status_json: bool) -> ! {
+    if let Some((cmd, on_success)) = THEN.get() {
+        run_then(cmd, *on_success, exit_code);
+    }
+    if status_json {
+        let status = if exit_code == 0 { "ok" } else { "error" };
+        let processed = PROCESSED.load(Ordering::Relaxed);
+        let failed = FAILED.load(Ordering::Relaxed);
+        eprintln!(
+            "{{\"status\":\"{status}\",\"exit_code\":{exit_code},\"processed\":{processed},\"failed\":{failed}}}"
+        );
+    }
+    process::exit(exit_code);
+}
+
+// Helper function to parse the command line, ending the run through `finish`
+// so usage errors still get a status line
+fn parse_cli(status_json: bool) -> Cli {
+    match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(err) => {
+            let _ = err.print();
+            finish(err.exit_code(), status_json);
+        }
+    }
+}
+
+// Helper function to parse dotenv-style KEY=VALUE lines, allowing `#` comments,
+// an `export ` prefix and single- or double-quoted values
+fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=VALUE", index + 1))?;
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let (quoted, rest) = value[1..]
+                    .split_once(quote)
+                    .ok_or_else(|| format!("line {}: unterminated {quote} quote", index + 1))?;
+                // Only a `#` comment may follow the closing quote
+                let rest = rest.trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(format!(
+                        "line {}: unexpected {:?} after the closing quote",
+                        index + 1,
+                        rest
+                    ));
+                }
+                quoted.to_string()
+            }
+            _ => value
+                .split_once(" #")
+                .map_or(value, |(value, _)| value)
+                .trim_end()
+                .to_string(),
+        };
+        let key = key.trim();
+        if key.is_empty() || key.contains(['=', '\0']) {
+            return Err(format!(
+                "line {}: invalid variable name {:?}",
+                index + 1,
+                key
+            ));
+        }
+        if value.contains('\0') {
+            return Err(format!("line {}: value contains a NUL byte", index + 1));
+        }
+        entries.push((key.to_string(), value));
+    }
+    Ok(entries)
+}
+
+// Helper function to parse the key=value (or key: value) lines of a --set-file
+fn parse_set_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Split at whichever separator comes first, so values may contain the other
+        let split = [
+            line.find('=').map(|at| (at, 1)),
+            line.find(": ").map(|at| (at, 2)),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .or_else(|| line.strip_suffix(':').map(|key| (key.len(), 1)));
+        let (key, value) = split
+            .map(|(at, len)| (&line[..at], &line[at + len..]))
+            .ok_or_else(|| format!("line {}: expected key=value or key: value", index + 1))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("line {}: missing key", index + 1));
+        }
+        entries.push((key.to_string(), value.trim().to_string()));
+    }
+    Ok(entries)
+}
+
+// Helper function to split a nested config key on a separator, honouring backslash escapes
+fn key_path(key: &str, separator: char) -> Result<Vec<String>, String> {
+    if separator == '\\' {
+        return Err("the key separator cannot be a backslash".to_string());
+    }
+    let mut segments = vec![String::new()];
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => segments.last_mut().unwrap().push(escaped),
+                None => return Err(format!("key '{key}' ends with a dangling backslash")),
+            },
+            c if c == separator => segments.push(String::new()),
+            c => segments.last_mut().unwrap().push(c),
+        }
+    }
+    if segments.iter().any(String::is_empty) {
+        return Err(format!("key '{key}' has an empty segment"));
+    }
+    Ok(segments)
+}
+
+// Helper function to list the dotted key paths of a YAML-style config, sorted
+//
+// Only mapping keys outside lists are collected: list items and everything
+// nested under them are skipped, as are the bodies of block scalars.
+fn config_keys(contents: &str) -> Vec<String> {
+    let mut stack: Vec<(usize, String)> = Vec::new();
+    let mut keys = Vec::new();
+    // Lines indented deeper than this (or list items at exactly this indent
+    // when the flag is set) belong to a skipped block scalar or list
+    let mut skip: Option<(usize, bool)> = None;
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - trimmed.len();
+        if let Some((depth, list)) = skip {
+            if indent > depth || (list && indent == depth && trimmed.starts_with('-')) {
+                continue;
+            }
+            skip = None;
+        }
+        if trimmed.starts_with('-') {
+            skip = Some((indent, true));
+            continue;
+        }
+        let Some((key, value)) = trimmed
+            .split_once(": ")
+            .or_else(|| trimmed.strip_suffix(':').map(|key| (key, "")))
+        else {
+            continue;
+        };
+        while stack.last().is_some_and(|(depth, _)| *depth >= indent) {
+            stack.pop();
+        }
+        // Escape dots so keys round-trip through key_path
+        let key = key.trim().trim_matches(['"', '\'']).replace('.', "\\.");
+        stack.push((indent, key));
+        let path: Vec<&str> = stack.iter().map(|(_, key)| key.as_str()).collect();
+        keys.push(path.join("."));
+        if value.trim_start().starts_with(['|', '>']) {
+            skip = Some((indent, false));
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+// Helper function to turn a dotted config key into a shell variable name
+fn shell_key(key: &str) -> String {
+    let mut name: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+// Helper function to take an exclusive advisory lock, optionally waiting for it
+fn acquire_lock(path: &Path, wait: Option<u64>) -> Result<File, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| format!("cannot open lock file {:?}: {}", path, err))?;
+    let deadline = wait.map(|secs| Instant::now() + Duration::from_secs(secs));
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) => match deadline {
+                Some(deadline) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(100))
+                }
+                _ => return Err(format!("lock {:?} is held by another instance", path)),
+            },
+            Err(TryLockError::Error(err)) => {
+                return Err(format!("cannot lock {:?}: {}", path, err))
+            }
+        }
+    }
+}
+
+// Helper function to render a path in the requested separator style
+fn styled_path(path: &Path, style: &PathStyle) -> String {
+    let rendered = path.to_string_lossy();
+    match style {
+        PathStyle::Native => rendered.into_owned(),
+        PathStyle::Slash => rendered.replace(std::path::MAIN_SEPARATOR, "/"),
+    }
+}
+
+// Helper function to parse key-value pairs
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    let pos = s
+        .find('=')
+        .ok_or_else(|| format!("invalid KEY=value: no `=` found in `{s}`"))?;
+    Ok((s[..pos].to_string(), s[pos + 1..].to_string()))
+}
+
+// Fields available to output templates
+const TEMPLATE_FIELDS: [&str; 3] = ["path", "size", "status"];
+
+// Fields available to destination templates
+const DESTINATION_FIELDS: [&str; 8] = [
+    "name", "stem", "ext", "parent", "date", "year", "month", "day",
+];
+
+// Helper function to validate an output template
+fn parse_template(s: &str) -> Result<String, String> {
+    check_template(s, &TEMPLATE_FIELDS)
+}
+
+// Helper function to validate a destination template, which becomes a path and so
+// takes no `{newline}` or `{tab}` escapes
+fn parse_destination_template(s: &str) -> Result<String, String> {
+    let expanded = check_template(s, &DESTINATION_FIELDS)?;
+    if expanded != s || s.chars().any(char::is_control) {
+        return Err(format!(
+            "invalid destination template: `{s}` would put a control character in a path"
+        ));
+    }
+    Ok(expanded)
+}
+
+// Helper function to validate a `{field}` template against a field set and expand its escapes
+fn check_template(s: &str, fields: &[&str]) -> Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("invalid template: unclosed `{{` in `{s}`"))?;
+        let field = &rest[start + 1..start + end];
+        match field {
+            "newline" => expanded.push('\n'),
+            "tab" => expanded.push('\t'),
+            _ if fields.contains(&field) => expanded.push_str(&rest[start..=start + end]),
+            _ => {
+                return Err(format!(
+                    "invalid template: unknown field `{field}` (expected one of: {})",
+                    fields.join(", ")
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Helper function to load a template from a file and validate it like --template
+fn parse_template_file(s: &str) -> Result<String, String> {
+    let contents =
+        fs::read_to_string(s).map_err(|err| format!("cannot read template {s:?}: {err}"))?;
+    let contents = contents.strip_suffix('\n').unwrap_or(&contents);
+    parse_template(contents.strip_suffix('\r').unwrap_or(contents))
+}
+
+// Helper function to parse a batch size or `auto`
+fn parse_batch_size(s: &str) -> Result<BatchSize, String> {
+    if s == "auto" {
+        return Ok(BatchSize::Auto);
+    }
+    match s.parse::<usize>() {
+        Ok(0) => Err("batch size must be at least 1".to_string()),
+        Ok(size) => Ok(BatchSize::Fixed(size)),
+        Err(_) => Err(format!(
+            "invalid batch size `{s}`: expected a number or `auto`"
+        )),
+    }
+}
+
+// Helper function to parse an octal permission mode such as 640, 0640 or 0o640
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    let mode = u32::from_str_radix(digits, 8)
+        .map_err(|_| format!("invalid mode `{s}`: expected octal digits"))?;
+    if mode > 0o7777 {
+        return Err(format!("invalid mode `{s}`: must be at most 7777"));
+    }
+    Ok(mode)
+}
+
+// Helper function to parse a human-readable size such as 512, 64K or 1.5GiB
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let value: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: expected a number in `{s}`"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("invalid size: unknown unit `{unit}` in `{s}`")),
+    };
+    Ok((value * multiplier as f64) as u64)
+}
+
+// Helper function to parse a default depth and PATH:NUM per-source overrides
+fn parse_max_depth(s: &str) -> Result<MaxDepth, String> {
+    let mut default = None;
+    let mut sources = Vec::new();
+    for entry in s.split(',').map(str::trim) {
+        let depth = |value: &str| {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("invalid depth `{value}` in `{entry}`"))
+        };
+        match entry.rsplit_once(':') {
+            Some((path, value)) if !path.is_empty() => {
+                sources.push((PathBuf::from(path), depth(value)?))
+            }
+            Some(_) => return Err(format!("missing source path in `{entry}`")),
+            None if default.is_some() => {
+                return Err(format!("more than one default depth in `{s}`"))
+            }
+            None => default = Some(depth(entry)?),
+        }
+    }
+    Ok(MaxDepth {
+        default: default.unwrap_or(10),
+        sources,
+    })
+}
+
+// Helper function to parse a sample fraction or count
+fn parse_sample(s: &str) -> Result<Sample, String> {
+    if let Ok(count) = s.parse::<usize>() {
+        return Ok(Sample::Count(count));
+    }
+    let fraction: f64 = s
+        .parse()
+        .map_err(|_| format!("invalid sample: expected a fraction or a count, got `{s}`"))?;
+    if fraction > 0.0 && fraction <= 1.0 {
+        Ok(Sample::Fraction(fraction))
+    } else {
+        Err(format!("invalid sample: fraction `{s}` must be in (0, 1]"))
+    }
+}
+
+// Small seeded generator (SplitMix64) so sampling is reproducible
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Helper function to select a reproducible subset of paths, keeping their order
+fn sample_paths(paths: Vec<PathBuf>, sample: &Sample, seed: u64) -> Vec<PathBuf> {
+    let mut rng = SplitMix64(seed);
+    match *sample {
+        Sample::Fraction(fraction) => paths
+            .into_iter()
+            .filter(|_| rng.next_f64() < fraction)
+            .collect(),
+        Sample::Count(count) if count >= paths.len() => paths,
+        Sample::Count(count) => {
+            let mut indices: Vec<usize> = (0..paths.len()).collect();
+            for i in 0..count {
+                let j = i + (rng.next_u64() % (paths.len() - i) as u64) as usize;
+                indices.swap(i, j);
+            }
+            let mut chosen = indices[..count].to_vec();
+            chosen.sort_unstable();
+            chosen.into_iter().map(|i| paths[i].clone()).collect()
+        }
+    }
+}
+
+// Helper function to put paths in the requested processing order
+fn order_paths(mut paths: Vec<PathBuf>, order: &InputOrder, seed: u64) -> Vec<PathBuf> {
+    match order {
+        InputOrder::AsGiven => {}
+        InputOrder::Sorted => paths.sort(),
+        InputOrder::Random => {
+            let mut rng = SplitMix64(seed);
+            for i in (1..paths.len()).rev() {
+                let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+                paths.swap(i, j);
+            }
+        }
+    }
+    paths
+}
+
+// Helper function to pick an output format from a file extension
+fn infer_format(path: &Path) -> Option<OutputFormat> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "json" => Some(OutputFormat::Json),
+        "yaml" | "yml" => Some(OutputFormat::Yaml),
+        "txt" => Some(OutputFormat::Text),
+        _ => None,
+    }
+}
+
+// Helper function to read input paths from a list file, one per line
+fn read_input_list(path: &Path) -> Result<Vec<PathBuf>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("cannot read input list {:?}: {}", path, err))?;
+    Ok(contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+// Helper function to tell whether an input path means stdin
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// Helper function to get a file's size and modification time in seconds for plans
+fn plan_stamp(path: &Path) -> Result<(u64, u64), String> {
+    let metadata = fs::metadata(path).map_err(|err| format!("cannot stat {:?}: {}", path, err))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_secs());
+    Ok((metadata.len(), modified))
+}
+
+// Helper function to escape the backslashes, tabs and newlines of a path in a plan line
+fn escape_plan_path(path: &str) -> String {
+    path.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+// Helper function to undo escape_plan_path
+fn unescape_plan_path(escaped: &str) -> String {
+    let mut path = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => path.push('\t'),
+            Some('n') => path.push('\n'),
+            Some('r') => path.push('\r'),
+            Some(other) => path.push(other),
+            None => path.push('\\'),
+        }
+    }
+    path
+}
+
+// Helper function to save a dry-run plan as size, mtime and path lines
+fn write_plan(plan: &Path, paths: &[PathBuf]) -> Result<(), String> {
+    let mut contents = String::from("# mycli plan v1\n");
+    for path in paths {
+        if is_stdin(path) {
+            return Err(
+                "stdin (`-`) cannot be recorded in a plan; pass a file instead".to_string(),
+            );
+        }
+        let (size, modified) = plan_stamp(path)?;
+        let path = path.to_str().ok_or_else(|| {
+            format!(
+                "{:?} is not valid UTF-8 and cannot be recorded in a plan",
+                path
+            )
+        })?;
+        contents.push_str(&format!(
+            "{}\t{}\t{}\n",
+            size,
+            modified,
+            escape_plan_path(path)
+        ));
+    }
+    fs::write(plan, contents).map_err(|err| format!("cannot write plan {:?}: {}", plan, err))
+}
+
+// Helper function to load a plan and check that none of its inputs have drifted
+fn read_plan(plan: &Path) -> Result<Vec<PathBuf>, String> {
+    let contents =
+        fs::read_to_string(plan).map_err(|err| format!("cannot read plan {:?}: {}", plan, err))?;
+    let mut lines = contents.lines();
+    if lines.next() != Some("# mycli plan v1") {
+        return Err(format!("{:?} is not a plan written by --plan-file", plan));
+    }
+    let mut paths = Vec::new();
+    for (index, line) in lines.enumerate() {
+        let mut fields = line.splitn(3, '\t');
+        let size = fields.next().and_then(|field| field.parse::<u64>().ok());
+        let modified = fields.next().and_then(|field| field.parse::<u64>().ok());
+        let (Some(size), Some(modified), Some(path)) = (size, modified, fields.next()) else {
+            return Err(format!(
+                "{:?} line {}: malformed plan entry",
+                plan,
+                index + 2
+            ));
+        };
+        let path = PathBuf::from(unescape_plan_path(path));
+        let stamp =
+            plan_stamp(&path).map_err(|_| format!("planned input {:?} no longer exists", path))?;
+        if stamp != (size, modified) {
+            return Err(format!(
+                "planned input {:?} changed since the plan was written",
+                path
+            ));
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+// Helper function to build a command that runs CMD through the platform shell
+fn shell_command(cmd: &str) -> ShellCommand {
+    let mut command = if cfg!(windows) {
+        ShellCommand::new("cmd")
+    } else {
+        ShellCommand::new("sh")
+    };
+    command.args([if cfg!(windows) { "/C" } else { "-c" }, cmd]);
+    command
+}
+
+// Helper function to run a shell command and read input paths from its stdout
+fn read_cmd_inputs(cmd: &str, null: bool) -> Result<Vec<PathBuf>, String> {
+    let output = shell_command(cmd)
+        .output()
+        .map_err(|err| format!("cannot run `{cmd}`: {err}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "`{cmd}` failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    let separator = if null { b'\0' } else { b'\n' };
+    Ok(output
+        .stdout
+        .split(|&byte| byte == separator)
+        .map(|raw| {
+            String::from_utf8_lossy(raw)
+                .trim_end_matches('\r')
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+// Helper function to expand a glob into the files it matches, in sorted order
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let components: Vec<&str> = pattern.split('/').collect();
+    let literal: Vec<&str> = components
+        .iter()
+        .take_while(|component| !component.contains(['*', '?']))
+        .copied()
+        .collect();
+    if literal.len() == components.len() {
+        let path = PathBuf::from(pattern);
+        return if path.exists() {
+            vec![path]
+        } else {
+            Vec::new()
+        };
+    }
+    let base = if literal.is_empty() {
+        PathBuf::from(".")
+    } else {
+        PathBuf::from(literal.join("/"))
+    };
+    // Without `**` a match can sit no deeper than the pattern itself
+    let depth = if components.contains(&"**") {
+        u32::MAX
+    } else {
+        (components.len() - literal.len()) as u32
+    };
+    let mut files = Vec::new();
+    collect_files(&base, depth, &mut files);
+    files
+        .into_iter()
+        .map(|path| match path.strip_prefix(".") {
+            Ok(relative) if literal.is_empty() => relative.to_path_buf(),
+            _ => path,
+        })
+        .filter(|path| glob_match(pattern, &path.to_string_lossy()))
+        .collect()
+}
+
+// Match a `/`-separated path against a glob where `*` and `?` stay within
+// one component and `**` spans any number of components
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    match_components(&pattern, &path)
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_components(rest, &path[skip..])),
+        Some((first, rest)) => {
+            !path.is_empty()
+                && match_wildcard(
+                    &first.chars().collect::<Vec<_>>(),
+                    &path[0].chars().collect::<Vec<_>>(),
+                )
+                && match_components(rest, &path[1..])
+        }
+    }
+}
+
+fn match_wildcard(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| match_wildcard(rest, &text[skip..])),
+        Some(('?', rest)) => !text.is_empty() && match_wildcard(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && match_wildcard(rest, &text[1..]),
+    }
+}
+
+// Helper function to expand directories into the files they contain. With
+// `keep_archives`, archives are kept whatever the patterns say, since the
+// patterns are matched against their members later.
+fn expand_inputs(
+    paths: Vec<PathBuf>,
+    max_depth: &MaxDepth,
+    patterns: &[String],
+    keep_archives: bool,
+) -> Vec<PathBuf> {
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        if path.is_dir() {
+            let mut found = Vec::new();
+            collect_files(&path, max_depth.for_source(&path), &mut found);
+            files.extend(found.into_iter().filter(|file| {
+                let relative = file.strip_prefix(&path).unwrap_or(file);
+                (keep_archives && listable_archive(file))
+                    || patterns_select(patterns, &relative.to_string_lossy())
+            }));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+// Helper function to list the regular files in an uncompressed tar archive
+fn tar_members(data: &[u8]) -> Result<Vec<String>, String> {
+    let field = |block: &[u8], start: usize, len: usize| {
+        let raw = &block[start..start + len];
+        let end = raw.iter().position(|&b| b == 0).unwrap_or(len);
+        String::from_utf8_lossy(&raw[..end]).into_owned()
+    };
+    let mut members = Vec::new();
+    let mut long_name = None;
+    let mut offset = 0;
+    while offset + 512 <= data.len() {
+        let header = &data[offset..offset + 512];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size_field = field(header, 124, 12);
+        let size = u64::from_str_radix(size_field.trim(), 8)
+            .map_err(|_| format!("bad member size at offset {offset}"))?;
+        let body = data
+            .get(offset + 512..offset + 512 + size as usize)
+            .ok_or_else(|| format!("truncated member at offset {offset}"))?;
+        let name = match field(header, 345, 155) {
+            prefix if !prefix.is_empty() && &header[257..262] == b"ustar" => {
+                format!("{}/{}", prefix, field(header, 0, 100))
+            }
+            _ => field(header, 0, 100),
+        };
+        match header[156] {
+            b'0' | 0 => members.push(long_name.take().unwrap_or(name)),
+            // GNU long name: the body is the next member's full name
+            b'L' => long_name = Some(field(body, 0, body.len())),
+            // PAX extended header: `LEN key=value` records, of which path renames
+            b'x' => {
+                long_name = String::from_utf8_lossy(body).lines().find_map(|record| {
+                    record
+                        .split_once(" path=")
+                        .map(|(_, path)| path.to_string())
+                })
+            }
+            _ => long_name = None,
+        }
+        offset += 512 + (size as usize).div_ceil(512) * 512;
+    }
+    Ok(members)
+}
+
+// Helper function to list the files in a zip archive from its central directory
+fn zip_members(data: &[u8]) -> Result<Vec<String>, String> {
+    let u16_at = |at: usize| {
+        data.get(at..at + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+    };
+    let u32_at = |at: usize| {
+        data.get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .find(|&at| data[at..].starts_with(b"PK\x05\x06"))
+        .ok_or("no zip end-of-central-directory record")?;
+    let count = u16_at(end + 10).ok_or("truncated zip directory")?;
+    let mut at = u32_at(end + 16).ok_or("truncated zip directory")?;
+    let mut members = Vec::new();
+    for _ in 0..count {
+        if !data
+            .get(at..)
+            .is_some_and(|rest| rest.starts_with(b"PK\x01\x02"))
+        {
+            return Err("corrupt zip central directory".to_string());
+        }
+        let (Some(name_len), Some(extra_len), Some(comment_len)) =
+            (u16_at(at + 28), u16_at(at + 30), u16_at(at + 32))
+        else {
+            return Err("truncated zip directory".to_string());
+        };
+        let name = data
+            .get(at + 46..at + 46 + name_len)
+            .ok_or("truncated zip directory")?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        if !name.ends_with('/') {
+            members.push(name);
+        }
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(members)
+}
+
+// Helper function to tell whether expand_archives can list a path's members
+fn listable_archive(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_lowercase();
+    name.ends_with(".tar") || name.ends_with(".zip")
+}
+
+// Helper function to replace archive inputs with `archive!member` paths
+fn expand_archives(paths: Vec<PathBuf>, patterns: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut inputs = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = path.to_string_lossy().to_lowercase();
+        let list: fn(&[u8]) -> Result<Vec<String>, String> = if name.ends_with(".tar") {
+            tar_members
+        } else if name.ends_with(".zip") {
+            zip_members
+        } else {
+            if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+                warn(&format!(
+                    "cannot list compressed archive {:?}; processing it whole",
+                    path
+                ));
+            }
+            inputs.push(path);
+            continue;
+        };
+        let data = fs::read(&path).map_err(|err| format!("cannot read {:?}: {}", path, err))?;
+        let members = list(&data).map_err(|err| format!("{:?}: {}", path, err))?;
+        inputs.extend(
+            members
+                .into_iter()
+                .filter(|member| patterns_select(patterns, member))
+                .map(|member| PathBuf::from(format!("{}!{}", path.display(), member))),
+        );
+    }
+    Ok(inputs)
+}
+
+// Decide whether an ordered list of patterns, where `!` negates, selects a path.
+// The last matching pattern wins; with no positive patterns everything starts selected.
+fn patterns_select(patterns: &[String], path: &str) -> bool {
+    let mut selected = patterns.iter().all(|pattern| pattern.starts_with('!'));
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_match(negated, path) => selected = false,
+            None if glob_match(pattern, path) => selected = true,
+            _ => {}
+        }
+    }
+    selected
+}
+
+// Walk a directory in sorted order, descending at most `depth` levels.
+// Symlinked directories are not followed, so a link cycle cannot loop.
+fn collect_files(dir: &Path, depth: u32, files: &mut Vec<PathBuf>) {
+    if depth == 0 {
+        return;
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn(&format!("cannot read directory {:?}: {}", dir, err));
+            return;
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            let linked =
+                fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink());
+            if !linked {
+                collect_files(&path, depth - 1, files);
+            }
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+// Helper function to split NUL-separated bytes into paths
+fn split_nul_paths(input: &[u8]) -> Vec<PathBuf> {
+    input
+        .split(|&byte| byte == 0)
+        .filter(|raw| !raw.is_empty())
+        .map(|raw| PathBuf::from(String::from_utf8_lossy(raw).into_owned()))
+        .collect()
+}
+
+// Helper function to find the deepest directory containing every source
+fn common_base(sources: &[PathBuf]) -> PathBuf {
+    let mut parents = sources
+        .iter()
+        .map(|source| source.parent().unwrap_or(Path::new("")));
+    let Some(first) = parents.next() else {
+        return PathBuf::new();
+    };
+    let mut base: Vec<Component> = first.components().collect();
+    for parent in parents {
+        let shared = base
+            .iter()
+            .zip(parent.components())
+            .take_while(|(a, b)| **a == *b)
+            .count();
+        base.truncate(shared);
+    }
+    base.iter().collect()
+}
+
+// Helper function to map sources to the same relative path under the destination
+fn mirror_paths(
+    sources: Vec<PathBuf>,
+    base: Option<&Path>,
+    destination: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    sources
+        .into_iter()
+        .map(|source| {
+            let relative = match base {
+                Some(base) => source
+                    .strip_prefix(base)
+                    .map_err(|_| format!("{:?} is not under --base {:?}", source, base))?
+                    .to_path_buf(),
+                None => source.clone(),
+            };
+            if relative.is_absolute() {
+                return Err(format!(
+                    "{:?} is absolute; pass --base to strip a prefix",
+                    source
+                ));
+            }
+            if relative
+                .components()
+                .any(|component| matches!(component, Component::ParentDir))
+            {
+                return Err(format!(
+                    "{:?} would be placed outside the destination",
+                    source
+                ));
+            }
+            let target = destination.join(relative);
+            Ok((source, target))
+        })
+        .collect()
+}
+
+// Helper function to tell whether a destination file is at least as new as its source
+fn up_to_date(source: &Path, target: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(source), modified(target)) {
+        (Ok(source), Ok(target)) => target >= source,
+        _ => false,
+    }
+}
+
+// Helper function to find a directory symlink that would lead a copy outside the destination
+fn escaping_symlink(target: &Path, destination: &Path) -> Option<PathBuf> {
+    let root = fs::canonicalize(destination).ok()?;
+    let relative = target.strip_prefix(destination).ok()?.parent()?;
+    let mut current = destination.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        let is_link =
+            fs::symlink_metadata(&current).is_ok_and(|meta| meta.file_type().is_symlink());
+        if !is_link {
+            continue;
+        }
+        match fs::canonicalize(&current) {
+            Ok(real) if real.starts_with(&root) => {}
+            _ => return Some(current),
+        }
+    }
+    None
+}
+
+// Helper function to turn days since the Unix epoch into a UTC (year, month, day)
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Helper function to compute each source's destination from a --destination-template
+fn template_paths(
+    sources: &[PathBuf],
+    template: &str,
+    destination: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    let mut pairs = Vec::with_capacity(sources.len());
+    for source in sources {
+        let text = |part: Option<&std::ffi::OsStr>| {
+            part.map(|part| part.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let modified = fs::metadata(source)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|err| format!("cannot read mtime of {:?}: {}", source, err))?;
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let (year, month, day) = civil_date(secs.div_euclid(86_400));
+        let mut rendered = String::new();
+        let mut rest = template;
+        // parse_destination_template has already checked every `{` is closed
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                break;
+            };
+            rendered.push_str(&match &rest[start + 1..end] {
+                "name" => text(source.file_name()),
+                "stem" => text(source.file_stem()),
+                "ext" => text(source.extension()),
+                "parent" => text(source.parent().and_then(Path::file_name)),
+                "date" => format!("{year:04}-{month:02}-{day:02}"),
+                "year" => format!("{year:04}"),
+                "month" => format!("{month:02}"),
+                "day" => format!("{day:02}"),
+                field => format!("{{{field}}}"),
+            });
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+        let mut target = destination.to_path_buf();
+        for segment in rendered.split(['/', '\\']) {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    return Err(format!(
+                        "destination template puts {:?} outside the destination",
+                        source
+                    ))
+                }
+                segment => target.push(segment),
+            }
+        }
+        if target == destination {
+            return Err(format!(
+                "destination template gives {:?} an empty path",
+                source
+            ));
+        }
+        pairs.push((source.clone(), target));
+    }
+    check_unique_targets(&pairs)?;
+    Ok(pairs)
+}
+
+// Helper function to refuse targets whose parent directory does not exist yet
+fn check_parents(pairs: &[(PathBuf, PathBuf)]) -> Result<(), String> {
+    for (_, target) in pairs {
+        if let Some(parent) = target.parent() {
+            if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                return Err(format!(
+                    "destination directory {:?} does not exist (pass --parents to create it)",
+                    parent
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Helper function to refuse copying into a non-empty destination directory
+// unless --merge or --force allows it
+fn check_destination(destination: &Path, allow_non_empty: bool) -> Result<(), String> {
+    let non_empty = fs::read_dir(destination).is_ok_and(|mut entries| entries.next().is_some());
+    if non_empty && !allow_non_empty {
+        return Err(format!(
+            "destination {:?} is not empty (pass --merge to merge into it or --force to copy anyway)",
+            destination
+        ));
+    }
+    Ok(())
+}
+
+// Helper function to refuse a plan that copies two sources onto the same target
+fn check_unique_targets(pairs: &[(PathBuf, PathBuf)]) -> Result<(), String> {
+    let mut seen: HashMap<&Path, &Path> = HashMap::new();
+    for (source, target) in pairs {
+        if let Some(first) = seen.insert(target, source) {
+            return Err(format!(
+                "{:?} and {:?} would both be copied to {:?}",
+                first, source, target
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Helper function to map sources directly into the destination by file name
+fn flatten_paths(
+    sources: &[PathBuf],
+    destination: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    let pairs: Vec<(PathBuf, PathBuf)> = sources
+        .iter()
+        .map(|source| {
+            let target = match source.file_name() {
+                Some(name) => destination.join(name),
+                None => destination.to_path_buf(),
+            };
+            (source.clone(), target)
+        })
+        .collect();
+    check_unique_targets(&pairs)?;
+    Ok(pairs)
+}
+
+// Bytes read to calibrate the dry-run duration estimate
+const CALIBRATION_BYTES: u64 = 8 << 20;
+
+// Helper function to total the size of `paths` and estimate how fast they can be
+// read, from timing a read of the first few MiB. Returns (bytes, bytes per second);
+// the rate covers reading only, not writing output.
+fn estimate_cost(paths: &[PathBuf]) -> (u64, Option<f64>) {
+    let total = paths
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let started = Instant::now();
+    let mut sampled = 0u64;
+    let mut buffer = vec![0; 64 * 1024];
+    for path in paths {
+        let Ok(mut file) = File::open(path) else {
+            continue;
+        };
+        while sampled < CALIBRATION_BYTES {
+            match file.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => sampled += read as u64,
+            }
+        }
+        if sampled >= CALIBRATION_BYTES {
+            break;
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    let throughput = (sampled > 0 && elapsed > 0.0).then(|| sampled as f64 / elapsed);
+    (total, throughput)
+}
+
+// Helper function to canonicalize paths and drop duplicate spellings
+fn normalize_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut normalized = Vec::with_capacity(paths.len());
+    for path in paths {
+        let canonical = if is_stdin(&path) {
+            path
+        } else {
+            fs::canonicalize(&path).unwrap_or(path)
+        };
+        if seen.insert(canonical.clone()) {
+            normalized.push(canonical);
+        } else {
+            warn(&format!("dropping duplicate input {:?}", canonical));
+        }
+    }
+    normalized
+}
+
+// Helper function to check config files on up to `threads` threads, keeping input order
+fn verify_files(paths: &[PathBuf], threads: usize) -> Vec<Result<usize, String>> {
+    let chunk = paths.len().div_ceil(threads.max(1)).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = paths
+            .chunks(chunk)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| {
+                            fs::read_to_string(path)
+                                .map(|contents| contents.lines().count())
+                                .map_err(|err| err.to_string())
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    })
+}
+
+// Helper function to quote a string as a JSON string literal
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Helper function to tell whether an option consumes a value
+fn arg_takes_value(arg: &clap::Arg) -> bool {
+    arg.get_num_args().is_some_and(|range| range.takes_values()) || arg.get_action().takes_values()
+}
+
+// Helper function to name the type an option's value is parsed into
+fn arg_type(arg: &clap::Arg) -> &'static str {
+    let id = arg.get_value_parser().type_id();
+    if !arg_takes_value(arg) {
+        "bool"
+    } else if !arg.get_possible_values().is_empty() {
+        "enum"
+    } else if id == TypeId::of::<PathBuf>() {
+        "path"
+    } else if id == TypeId::of::<u32>() || id == TypeId::of::<u64>() || id == TypeId::of::<usize>()
+    {
+        "integer"
+    } else if id == TypeId::of::<f64>() {
+        "number"
+    } else if id == TypeId::of::<char>() {
+        "char"
+    } else if id == TypeId::of::<(String, String)>() {
+        "key=value"
+    } else {
+        "string"
+    }
+}
+
+// Helper function to find a flag such as --help-json in flag position, skipping
+// option values and anything after `--`
+fn flag_requested(args: &[String], flag: &str) -> bool {
+    let root = Cli::command();
+    let mut commands = vec![&root];
+    let mut tokens = args.iter();
+    while let Some(token) = tokens.next() {
+        if token == "--" {
+            return false;
+        }
+        if token == flag {
+            return true;
+        }
+        let lookup = |matches: &dyn Fn(&clap::Arg) -> bool| {
+            commands
+                .iter()
+                .rev()
+                .flat_map(|command| command.get_arguments())
+                .find(|arg| matches(arg))
+                .is_some_and(arg_takes_value)
+        };
+        let expects_value = if let Some(long) = token.strip_prefix("--") {
+            !long.contains('=') && lookup(&|arg| arg.get_long() == Some(long))
+        } else if let Some(short) = token.strip_prefix('-') {
+            let mut chars = short.chars();
+            match (chars.next(), chars.next()) {
+                (Some(flag), None) => lookup(&|arg| arg.get_short() == Some(flag)),
+                _ => false,
+            }
+        } else {
+            let current = commands[commands.len() - 1];
+            if let Some(sub) = current.find_subcommand(token) {
+                commands.push(sub);
+            }
+            false
+        };
+        if expects_value {
+            tokens.next();
+        }
+    }
+    false
+}
+
+// Helper function to describe a command, its options and subcommands as JSON
+fn command_json(command: &Command) -> String {
+    let list = |items: Vec<String>| format!("[{}]", items.join(","));
+    let optional = |value: Option<String>| value.map_or("null".to_string(), |v| json_string(&v));
+    let args: Vec<String> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .map(|arg| {
+            format!(
+                "{{\"name\":{},\"long\":{},\"short\":{},\"help\":{},\"type\":{},\"takes_value\":{},\
+                 \"value_names\":{},\"possible_values\":{},\"defaults\":{},\"env\":{},\
+                 \"required\":{},\"global\":{}}}",
+                json_string(arg.get_id().as_str()),
+                optional(arg.get_long().map(str::to_string)),
+                optional(arg.get_short().map(String::from)),
+                optional(arg.get_help().map(ToString::to_string)),
+                json_string(arg_type(arg)),
+                arg_takes_value(arg),
+                list(
+                    arg.get_value_names()
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|name| json_string(name.as_str()))
+                        .collect()
+                ),
+                list(
+                    arg.get_possible_values()
+                        .iter()
+                        .map(|value| json_string(value.get_name()))
+                        .collect()
+                ),
+                list(
+                    arg.get_default_values()
+                        .iter()
+                        .map(|value| json_string(&value.to_string_lossy()))
+                        .collect()
+                ),
+                optional(
+                    arg.get_env()
+                        .map(|name| name.to_string_lossy().into_owned())
+                ),
+                arg.is_required_set(),
+                arg.is_global_set()
+            )
+        })
+        .collect();
+    let subcommands = command.get_subcommands().map(command_json).collect();
+    format!(
+        "{{\"name\":{},\"about\":{},\"args\":{},\"subcommands\":{}}}",
+        json_string(command.get_name()),
+        optional(command.get_about().map(ToString::to_string)),
+        list(args),
+        list(subcommands)
+    )
+}
+
+fn main() {
+    let raw_args: Vec<String> = env::args().skip(1).collect();
+    // Looked up by hand so the flag also applies when parsing fails
+    let status_json = flag_requested(&raw_args, "--status-json");
+
+    // Checked before parsing, since a normal parse would demand a subcommand
+    if flag_requested(&raw_args, "--help-json") {
+        println!("{}", command_json(&Cli::command()));
+        finish(0, status_json);
+    }
+
+    let mut cli = parse_cli(status_json);
+    let log_file = match &cli.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(cli.log_file_append)
+                .truncate(!cli.log_file_append)
+                .open(path);
+            match file {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    eprintln!("Error: cannot open log file {:?}: {}", path, err);
+                    finish(1, status_json);
+                }
+            }
+        }
+        None => None,
+    };
+    let file_level = cli.log_file_level.as_ref().unwrap_or(&cli.log_level);
+    let _ = LOGGER.set(Logger {
+        json: matches!(cli.log_format, LogFormat::Json),
+        file: log_file
+            .filter(|_| !matches!(file_level, LogLevel::Error))
+            .map(Mutex::new),
+    });
+
+    // Variables from the env file only fill gaps, so real environment wins;
+    // parse again so clap sees them
+    if let Some(path) = cli.config_env_file.clone() {
+        let entries = fs::read_to_string(&path)
+            .map_err(|err| format!("cannot read {:?}: {}", path, err))
+            .and_then(|contents| {
+                parse_env_file(&contents).map_err(|err| format!("{:?} {}", path, err))
+            });
+        match entries {
+            Ok(entries) => {
+                for (key, value) in entries {
+                    if !key.starts_with("MYCLI_") {
+                        warn(&format!(
+                            "ignoring {key} from {:?}: only MYCLI_* variables are loaded",
+                            path
+                        ));
+                    } else if env::var_os(&key).is_none() {
+                        env::set_var(key, value);
+                    }
+                }
+                cli = parse_cli(status_json);
+            }
+            Err(err) => {
+                eprintln!("Error: {err}");
+                finish(1, cli.status_json);
+            }
+        }
+    }
+
+    // The lock is released when the file is closed, including on abnormal exit
+    let _lock = match &cli.lock {
+        Some(path) => match acquire_lock(path, cli.lock_wait) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                eprintln!("Error: {err}");
+                finish(1, cli.status_json);
+            }
+        },
+        None => None,
+    };
+
+    // Set up logging based on global options
+    println!("Log level set to: {:?}", cli.log_level);
+    println!("Log format set to: {:?}", cli.log_format);
+    println!("Log order set to: {:?}", cli.log_order);
+    if let Some(profile) = &cli.profile {
+        println!("Config profile: {}", profile);
+    }
+    if let Some(log_file) = &cli.log_file {
+        let file_level = cli.log_file_level.as_ref().unwrap_or(&cli.log_level);
+        let mode = if cli.log_file_append {
+            "append"
+        } else {
+            "truncate"
+        };
+        println!(
+            "Log file: {:?} (level: {:?}, mode: {})",
+            log_file, file_level, mode
+        );
+    }
+    if cli.verbose {
+        println!("Verbose mode enabled");
+    }
+    if cli.line_buffered {
+        println!("Line buffered output enabled");
+    }
+    if cli.json_pretty {
+        println!("Pretty JSON output enabled");
+    }
+    if cli.show_skipped {
+        println!("Showing skipped files");
+    }
+    if cli.quiet_errors {
+        println!("Per-file errors suppressed");
+    }
+
+    // Handle commands
     match cli.command {
-        Commands::Files(args) => {
+        Commands::Files(mut args) => {
+            if args.stdin0 && io::stdin().is_terminal() && !cli.force_stdin {
+                eprintln!("Error: --stdin0 expects piped paths but stdin is a terminal; did you mean to pipe in find -print0? (pass --force-stdin to read it anyway)");
+                finish(1, cli.status_json);
+            }
+            if args.interactive && !io::stdin().is_terminal() {
+                eprintln!("Error: --interactive needs a terminal on stdin to ask about conflicts");
+                finish(1, cli.status_json);
+            }
+            if args.normalize {
+                args.source = normalize_paths(args.source);
+            }
+            let mut copies = Vec::new();
+            if let Some(destination) = args.destination.as_deref() {
+                let result = if args.stdin0 {
+                    let mut input = Vec::new();
+                    io::stdin()
+                        .read_to_end(&mut input)
+                        .map_err(|err| format!("cannot read paths from stdin: {err}"))
+                        .and_then(|_| {
+                            mirror_paths(split_nul_paths(&input), args.base.as_deref(), destination)
+                        })
+                } else if args.preserve_structure {
+                    let base = args
+                        .base
+                        .clone()
+                        .unwrap_or_else(|| common_base(&args.source));
+                    mirror_paths(args.source.clone(), Some(&base), destination)
+                } else if let Some(template) = &args.destination_template {
+                    template_paths(&args.source, template, destination)
+                } else {
+                    flatten_paths(&args.source, destination)
+                };
+                match result {
+                    Ok(pairs) => copies = pairs,
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            if !args.keep_dest_symlinks {
+                if let Some(destination) = args.destination.as_deref() {
+                    if let Some(link) = copies
+                        .iter()
+                        .find_map(|(_, target)| escaping_symlink(target, destination))
+                    {
+                        eprintln!("Error: {:?} is a symlink leading outside the destination (pass --keep-dest-symlinks to follow it)", link);
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            if !(args.parents || args.recursive) {
+                if let Err(err) = check_parents(&copies) {
+                    eprintln!("Error: {err}");
+                    finish(1, cli.status_json);
+                }
+            }
+            if let Some(destination) = args.destination.as_deref() {
+                if let Err(err) = check_destination(destination, args.merge || args.force) {
+                    eprintln!("Error: {err}");
+                    finish(1, cli.status_json);
+                }
+            }
             println!("Running Files command with args:");
-            println!("  Source: {:?}", args.source);
+            let sources: Vec<String> = args
+                .source
+                .iter()
+                .map(|source| styled_path(source, &cli.path_style))
+                .collect();
+            println!("  Source: {:?}", sources);
+            for (source, target) in &copies {
+                let action = if args.update && up_to_date(source, target) {
+                    if !cli.show_skipped {
+                        continue;
+                    }
+                    "Skipped (up to date)"
+                } else {
+                    PROCESSED.fetch_add(1, Ordering::Relaxed);
+                    "Copy"
+                };
+                println!(
+                    "  {}: {:?} -> {:?}",
+                    action,
+                    styled_path(source, &cli.path_style),
+                    styled_path(target, &cli.path_style)
+                );
+            }
             println!("  Destination: {:?}", args.destination);
             println!("  Recursive: {}", args.recursive);
             println!("  Patterns: {:?}", args.patterns);
+            println!("  Glob root: {:?}", args.glob_root);
             println!("  Max depth: {}", args.max_depth);
+            println!("  Create parents: {}", args.parents || args.recursive);
+            println!("  Merge: {}", args.merge);
+            println!("  Force: {}", args.force);
+            println!("  Only newer: {}", args.update);
+            println!("  Keep destination symlinks: {}", args.keep_dest_symlinks);
+            println!("  Interactive: {}", args.interactive);
+            println!("  Dedupe destination: {}", args.dedupe_destination);
+            if let Some(limit) = args.max_errors {
+                println!("  Max errors: {}", limit);
+            }
+            if args.preserve_xattr && !cfg!(any(target_os = "linux", target_os = "macos")) {
+                warn("--preserve-xattr is only supported on Linux and macOS; copies will not keep extended attributes");
+            }
+            println!("  Preserve xattrs: {}", args.preserve_xattr);
+            println!(
+                "  Preserve ownership: {}",
+                args.preserve_ownership && cfg!(unix)
+            );
+            println!("  Normalize: {}", args.normalize);
+            println!("  Template: {:?}", args.template.or(args.template_file));
         }
         Commands::Config(args) => {
+            let mutates = args.set.is_some() || args.set_file.is_some() || args.undo;
+            if cli.config_readonly && mutates && !args.dry_run {
+                eprintln!(
+                    "Error: {:?} is read-only (--config-readonly is set)",
+                    args.file
+                );
+                finish(1, cli.status_json);
+            }
+            let lock_file = args.lock_file.clone().unwrap_or_else(|| {
+                let mut path = args.file.clone().into_os_string();
+                path.push(".lock");
+                PathBuf::from(path)
+            });
+            if args.check_lock {
+                let keys = fs::read_to_string(&args.file)
+                    .map(|contents| config_keys(&contents))
+                    .map_err(|err| format!("cannot load {:?}: {}", args.file, err));
+                let locked = fs::read_to_string(&lock_file)
+                    .map_err(|err| format!("cannot read lock file {:?}: {}", lock_file, err));
+                match keys.and_then(|keys| locked.map(|locked| (keys, locked))) {
+                    Ok((keys, locked)) => {
+                        let locked: Vec<&str> = locked
+                            .lines()
+                            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                            .collect();
+                        let added: Vec<&String> = keys
+                            .iter()
+                            .filter(|key| !locked.contains(&key.as_str()))
+                            .collect();
+                        let removed: Vec<&&str> = locked
+                            .iter()
+                            .filter(|key| !keys.iter().any(|k| k == **key))
+                            .collect();
+                        if !added.is_empty() || !removed.is_empty() {
+                            eprintln!(
+                                "Error: {:?} has drifted from {:?} (added: {:?}, removed: {:?})",
+                                args.file, lock_file, added, removed
+                            );
+                            finish(1, cli.status_json);
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
             println!("Running Config command with args:");
             if let Some(set_values) = args.set {
-                println!("  Setting: {} = {}", set_values[0], set_values[1]);
+                let path = match key_path(&set_values[0], args.key_separator) {
+                    Ok(path) => path,
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                };
+                let verb = if args.dry_run { "Would set" } else { "Setting" };
+                println!("  {}: {} = {}", verb, set_values[0], set_values[1]);
+                println!("  Key path: {:?}", path);
+                if args.if_not_exists {
+                    println!("  Only if the key does not exist");
+                }
+                if args.if_exists {
+                    println!("  Only if the key already exists");
+                }
+                if let Some(cmd) = &args.validate_cmd {
+                    println!("  Validating with: {}", cmd);
+                }
+            }
+            if let Some(path) = &args.set_file {
+                let entries = fs::read_to_string(path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|contents| parse_set_file(&contents))
+                    .and_then(|entries| {
+                        for (key, _) in &entries {
+                            key_path(key, args.key_separator)?;
+                        }
+                        Ok(entries)
+                    });
+                match entries {
+                    Ok(entries) => {
+                        let verb = if args.dry_run { "Would set" } else { "Setting" };
+                        for (key, value) in &entries {
+                            println!("  {}: {} = {}", verb, key, value);
+                        }
+                        println!("  {} keys from {:?} in one write", entries.len(), path);
+                    }
+                    Err(err) => {
+                        eprintln!("Error: {:?}: {}; nothing was written", path, err);
+                        finish(1, cli.status_json);
+                    }
+                }
             }
-            if let Some(key) = args.get {
-                println!("  Getting value for: {}", key);
+            if let Some(keys) = args.get {
+                if args.decode.is_some() && keys.len() > 1 {
+                    eprintln!("Error: --decode takes exactly one --get key");
+                    finish(1, cli.status_json);
+                }
+                for key in keys {
+                    if let Err(err) = key_path(&key, args.key_separator) {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                    match args.format {
+                        ConfigFormat::Shell => {
+                            println!("  Getting value for: {} (as {})", key, shell_key(&key))
+                        }
+                        _ => println!("  Getting value for: {}", key),
+                    }
+                }
+                println!("  All required: {}", args.all_required);
+                if let Some(decode) = &args.decode {
+                    println!("  Decoding as: {:?} (raw bytes to stdout)", decode);
+                }
             }
-            if args.list {
+            if args.list && args.diff_from_defaults {
+                println!("  Listing configuration values that differ from the defaults");
+            } else if args.list {
                 println!("  Listing all configuration values");
             }
+            if args.history {
+                println!("  Listing the last {} backups", args.keep);
+            }
+            if args.undo {
+                let verb = if args.dry_run {
+                    "Would restore"
+                } else {
+                    "Restoring"
+                };
+                println!("  {} the most recent backup", verb);
+            }
+            if args.verify {
+                match fs::read_to_string(&args.file) {
+                    Ok(contents) => println!(
+                        "  Verified {:?}: {} lines readable",
+                        args.file,
+                        contents.lines().count()
+                    ),
+                    Err(err) => {
+                        eprintln!("Error: cannot load {:?}: {}", args.file, err);
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            if args.lock_keys {
+                let keys = fs::read_to_string(&args.file)
+                    .map(|contents| config_keys(&contents))
+                    .map_err(|err| format!("cannot load {:?}: {}", args.file, err));
+                let written = keys.and_then(|keys| {
+                    let contents = format!(
+                        "# mycli config lock v1\n{}",
+                        keys.iter()
+                            .map(|key| format!("{key}\n"))
+                            .collect::<String>()
+                    );
+                    if !args.dry_run {
+                        fs::write(&lock_file, contents).map_err(|err| {
+                            format!("cannot write lock file {:?}: {}", lock_file, err)
+                        })?;
+                    }
+                    Ok(keys.len())
+                });
+                match written {
+                    Ok(count) => {
+                        let verb = if args.dry_run { "Would lock" } else { "Locked" };
+                        println!("  {} {} keys in {:?}", verb, count, lock_file);
+                    }
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            if let Some(pattern) = &args.verify_glob {
+                let files = expand_glob(pattern);
+                if files.is_empty() {
+                    eprintln!("Error: --verify-glob '{pattern}' matched no files");
+                    finish(1, cli.status_json);
+                }
+                let results = verify_files(&files, args.threads);
+                PROCESSED.fetch_add(files.len(), Ordering::Relaxed);
+                let mut failed = 0;
+                for (path, result) in files.iter().zip(&results) {
+                    match result {
+                        Ok(lines) => println!("  PASS {:?}: {} lines readable", path, lines),
+                        Err(err) => {
+                            failed += 1;
+                            FAILED.fetch_add(1, Ordering::Relaxed);
+                            if !cli.quiet_errors {
+                                println!("  FAIL {:?}: {}", path, err);
+                            }
+                        }
+                    }
+                }
+                if failed > 0 {
+                    eprintln!("Error: {} of {} config files failed", failed, files.len());
+                    finish(1, cli.status_json);
+                }
+            }
             println!("  Using config file: {:?}", args.file);
+            println!("  Format: {:?}", args.format);
+            println!("  Strict: {}", args.strict);
         }
-        Commands::Process(args) => {
+        Commands::Process(mut args) => {
+            // Registered first so the hook also runs when the batch fails early
+            if let Some(cmd) = &args.then {
+                let _ = THEN.set((cmd.clone(), args.then_on_success));
+            }
+            if let Some(plan) = &args.apply_plan {
+                match read_plan(plan) {
+                    Ok(paths) => args.input_files.extend(paths),
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            if let Some(list) = &args.input_list {
+                match read_input_list(list) {
+                    Ok(paths) => args.input_files.extend(paths),
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            if let Some(cmd) = &args.files_from_cmd {
+                match read_cmd_inputs(cmd, args.null) {
+                    Ok(paths) => args.input_files.extend(paths),
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            if let Some(pattern) = &args.input_glob {
+                let matches = expand_glob(pattern);
+                if matches.is_empty() && !args.allow_empty {
+                    eprintln!("Error: --input-glob '{pattern}' matched no files (pass --allow-empty to continue)");
+                    finish(1, cli.status_json);
+                }
+                args.input_files.extend(matches);
+            }
+            // Checked once every input source has been gathered, since `-` may come from any of them
+            let reads_stdin = args.input_files.iter().any(|path| is_stdin(path));
+            if reads_stdin && io::stdin().is_terminal() && !cli.force_stdin {
+                eprintln!("Error: no input piped; did you mean to redirect a file? (pass --force-stdin to read from the terminal)");
+                finish(1, cli.status_json);
+            }
+            if args.recursive {
+                let patterns = args.patterns.clone().unwrap_or_default();
+                args.input_files = expand_inputs(
+                    args.input_files,
+                    &args.max_depth,
+                    &patterns,
+                    args.expand_archives,
+                );
+            }
+            if args.normalize {
+                args.input_files = normalize_paths(args.input_files);
+            }
+            let (present, missing): (Vec<PathBuf>, Vec<PathBuf>) = args
+                .input_files
+                .into_iter()
+                .partition(|path| is_stdin(path) || path.exists());
+            if let Some(first) = missing.first() {
+                if let OnMissingInput::Error = args.on_missing_input {
+                    FAILED.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("Error: input {:?} does not exist", first);
+                    finish(1, cli.status_json);
+                }
+                for path in &missing {
+                    if cli.quiet_errors {
+                        WARNINGS.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        warn(&format!("skipping missing input {:?}", path));
+                    }
+                    record_failure(args.max_errors, cli.status_json);
+                }
+            }
+            args.input_files = present;
+            let size_skipped = if args.min_size.is_some() || args.max_size.is_some() {
+                let before = args.input_files.len();
+                args.input_files.retain(|path| {
+                    if is_stdin(path) {
+                        return true;
+                    }
+                    let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+                    args.min_size.is_none_or(|min| size >= min)
+                        && args.max_size.is_none_or(|max| size <= max)
+                });
+                before - args.input_files.len()
+            } else {
+                0
+            };
+            if args.expand_archives {
+                let patterns = args.patterns.clone().unwrap_or_default();
+                match expand_archives(args.input_files, &patterns) {
+                    Ok(paths) => args.input_files = paths,
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                }
+            }
+            let seed = args.seed.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_nanos() as u64)
+                    .unwrap_or(0)
+            });
+            if let Some(sample) = &args.sample {
+                args.input_files = sample_paths(args.input_files, sample, seed);
+            }
+            args.input_files = order_paths(args.input_files, &args.input_order, seed);
+            PROCESSED.fetch_add(args.input_files.len(), Ordering::Relaxed);
             println!("Running Process command with args:");
-            println!("  Input files: {:?}", args.input_files);
-            println!("  Output format: {:?}", args.format);
+            let inputs: Vec<String> = args
+                .input_files
+                .iter()
+                .map(|path| styled_path(path, &cli.path_style))
+                .collect();
+            println!("  Input files: {:?}", inputs);
+            println!("  Skipped missing: {}", missing.len());
+            println!("  Skipped by size: {}", size_skipped);
+            println!("  Recursive: {}", args.recursive);
+            println!("  Expand archives: {}", args.expand_archives);
+            println!("  Patterns: {:?}", args.patterns);
+            println!("  Max depth: {}", args.max_depth);
+            if let Some(sample) = &args.sample {
+                println!("  Sample: {:?} (seed: {})", sample, seed);
+            }
+            match args.input_order {
+                InputOrder::Random => println!("  Input order: Random (seed: {})", seed),
+                ref order => println!("  Input order: {:?}", order),
+            }
+            let format = args
+                .format
+                .or_else(|| args.output.as_deref().and_then(infer_format))
+                .unwrap_or(OutputFormat::Text);
+            if let Some(output) = args.output.as_deref().filter(|_| args.output_append) {
+                let has_records = fs::metadata(output).is_ok_and(|metadata| metadata.len() > 0);
+                if has_records && matches!(format, OutputFormat::Json) {
+                    eprintln!(
+                        "Error: cannot append to {:?}: JSON output is a single closed array; \
+                         write to a new file or use --format yaml --yaml-stream",
+                        output
+                    );
+                    finish(1, cli.status_json);
+                }
+            }
+            println!("  Output: {:?}", args.output);
+            println!("  Output append: {}", args.output_append);
+            if let Some(compression) = &args.compress_output {
+                println!("  Compress output: {:?}", compression);
+            }
+            if let Some(socket) = &args.result_socket {
+                println!("  Result socket: {:?}", socket);
+            }
+            if let Some(dir) = &args.output_split {
+                let ext = args.output_ext.clone().unwrap_or_else(|| match format {
+                    OutputFormat::Json => "json".to_string(),
+                    OutputFormat::Yaml => "yaml".to_string(),
+                    OutputFormat::Text => "txt".to_string(),
+                });
+                println!(
+                    "  Output split: {:?} (extension: {}, layout: {:?})",
+                    dir, ext, args.output_dir_layout
+                );
+            }
+            if let Some(mode) = args.output_mode {
+                if cfg!(unix) {
+                    println!("  Output mode: {:04o}", mode);
+                } else {
+                    warn("--output-mode is only supported on Unix; using default permissions");
+                }
+            }
+            println!("  Output format: {:?}", format);
+            if args.yaml_stream {
+                println!("  YAML stream: {}", matches!(format, OutputFormat::Yaml));
+            }
+            println!(
+                "  Encoding: {} -> {} (on invalid: {:?})",
+                args.input_encoding, args.output_encoding, args.on_invalid
+            );
+            // Line transforms always apply in this order
+            let mut transforms = Vec::new();
+            if args.strip_ansi {
+                transforms.push("strip-ansi".to_string());
+            }
+            if args.trim {
+                transforms.push("trim".to_string());
+            }
+            if let Some(prefix) = &args.strip_prefix {
+                transforms.push(format!("strip-prefix {:?}", prefix));
+            }
+            if let Some(suffix) = &args.strip_suffix {
+                transforms.push(format!("strip-suffix {:?}", suffix));
+            }
+            if args.to_lower {
+                transforms.push("to-lower".to_string());
+            }
+            if args.to_upper {
+                transforms.push("to-upper".to_string());
+            }
+            println!("  Line transforms: {:?}", transforms);
+            let verb = if args.dry_run {
+                "Would replace"
+            } else {
+                "Replacing"
+            };
+            if let Some(replace) = &args.replace {
+                println!("  {} in place: {:?} -> {:?}", verb, replace[0], replace[1]);
+            }
+            if let Some(replace) = &args.replace_regex {
+                println!("  {} in place: /{}/ -> {:?}", verb, replace[0], replace[1]);
+            }
+            if let Some(suffix) = &args.backup_suffix {
+                println!("  Backup suffix: {}", suffix);
+            }
+            if let Some(dedup) = &args.dedup_lines {
+                println!(
+                    "  Dedup lines: {:?} (max memory: {} bytes)",
+                    dedup, args.dedup_max_mem
+                );
+            }
             println!("  Threads: {}", args.threads);
-            println!("  Batch size: {}", args.batch_size);
+            println!("  Schedule: {:?}", args.schedule);
+            let can_pin = cfg!(any(target_os = "linux", target_os = "windows"));
+            if args.pin_threads && !can_pin {
+                warn("--pin-threads is not supported on this platform; threads will not be pinned");
+            }
+            println!("  Pin threads: {}", args.pin_threads && can_pin);
+            if args.adaptive_threads {
+                println!(
+                    "  Adaptive threads below {} bytes free",
+                    args.min_free_memory
+                );
+            }
+            match args.max_open_files {
+                Some(limit) => println!("  Max open files: {}", limit),
+                None => println!("  Max open files: auto"),
+            }
+            match args.batch_size {
+                BatchSize::Fixed(size) => {
+                    if args.batch_target_ms.is_some() {
+                        warn("--batch-target-ms only applies to --batch-size auto");
+                    }
+                    println!("  Batch size: {}", size);
+                }
+                BatchSize::Auto => println!(
+                    "  Batch size: auto (target {} ms per batch)",
+                    args.batch_target_ms.unwrap_or(250)
+                ),
+            }
+            if args.mmap {
+                println!("  Memory map inputs from {} bytes", args.mmap_threshold);
+            }
             println!("  Dry run: {}", args.dry_run);
+            if let Some(limit) = args.max_errors {
+                println!("  Max errors: {}", limit);
+            }
+            if args.dry_run {
+                let (bytes, throughput) = estimate_cost(&args.input_files);
+                match throughput {
+                    Some(rate) => println!(
+                        "  Estimate: {} bytes, about {:.1}s at a sampled read rate of {:.1} MiB/s (writes not timed)",
+                        bytes,
+                        bytes as f64 / rate,
+                        rate / (1 << 20) as f64
+                    ),
+                    None => println!("  Estimate: {} bytes (estimate only)", bytes),
+                }
+                if let Some(plan) = &args.plan_file {
+                    if let Err(err) = write_plan(plan, &args.input_files) {
+                        eprintln!("Error: {err}");
+                        finish(1, cli.status_json);
+                    }
+                    println!("  Plan written to: {:?}", plan);
+                }
+            }
+            if let Some(plan) = &args.apply_plan {
+                println!("  Applying plan: {:?}", plan);
+            }
+            println!("  Checkpoint: {:?}", args.checkpoint);
+            if let Some(cmd) = &args.then {
+                let when = if args.then_on_success {
+                    "on success"
+                } else {
+                    "always"
+                };
+                println!("  Then ({}): {}", when, cmd);
+            }
+            let tmp_dir = args.tmp_dir.unwrap_or_else(std::env::temp_dir);
+            println!("  Temp dir: {:?} (keep: {})", tmp_dir, args.keep_temp);
+            println!("  Normalize: {}", args.normalize);
+            println!("  Template: {:?}", args.template.or(args.template_file));
             if let Some(options) = args.options {
                 println!("  Custom options:");
                 for (key, value) in options {
@@ -195,4 +3173,551 @@ fn main() {
             }
         }
     }
-}
\ No newline at end of file
+
+    let warnings = WARNINGS.load(Ordering::Relaxed);
+    if cli.fail_on_warning && warnings > 0 {
+        eprintln!("Error: {warnings} warning(s) emitted and --fail-on-warning is set");
+        finish(1, cli.status_json);
+    }
+    finish(0, cli.status_json);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Create an empty scratch directory for one test, unique to this process
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mycli-test-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Parse `mycli SUB ARGS...` the way main would
+    fn parse(sub: &str, args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["mycli", sub], args].concat())
+    }
+
+    #[test]
+    fn mirror_paths_stays_under_destination() {
+        let dst = Path::new("dst");
+        let planned = mirror_paths(vec![PathBuf::from("a/b.txt")], None, dst).unwrap();
+        assert_eq!(planned[0].1, PathBuf::from("dst/a/b.txt"));
+        assert!(mirror_paths(vec![PathBuf::from("../escape.txt")], None, dst).is_err());
+        assert!(mirror_paths(vec![PathBuf::from("a/../../escape.txt")], None, dst).is_err());
+        assert!(mirror_paths(vec![PathBuf::from("/etc/passwd")], None, dst).is_err());
+    }
+
+    #[test]
+    fn preserve_structure_stays_under_destination() {
+        let dst = Path::new("dst");
+        let base = Path::new("src");
+        let planned = mirror_paths(vec![PathBuf::from("src/a/b.txt")], Some(base), dst).unwrap();
+        assert_eq!(planned[0].1, PathBuf::from("dst/a/b.txt"));
+        let escaping = vec![PathBuf::from("src/../../escape.txt")];
+        assert!(mirror_paths(escaping, Some(base), dst).is_err());
+    }
+
+    #[test]
+    fn plans_round_trip_awkward_names() {
+        let dir = temp_dir("plan");
+        let input = dir.join("tab\there\nnew\\line");
+        fs::write(&input, "data").unwrap();
+        let plan = dir.join("plan");
+        write_plan(&plan, std::slice::from_ref(&input)).unwrap();
+        assert_eq!(fs::read_to_string(&plan).unwrap().lines().count(), 2);
+        assert_eq!(read_plan(&plan).unwrap(), vec![input]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn apply_plan_excludes_other_input_selection() {
+        assert!(parse("process", &["--apply-plan", "p"]).is_ok());
+        assert!(parse("process", &["--apply-plan", "p", "extra.txt"]).is_err());
+        assert!(parse("process", &["--apply-plan", "p", "--input-glob", "*.txt"]).is_err());
+        assert!(parse("process", &["--apply-plan", "p", "--sample", "0.5"]).is_err());
+        assert!(parse("process", &["--apply-plan", "p", "--input-order", "random"]).is_err());
+    }
+
+    #[test]
+    fn command_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn help_json_lists_every_subcommand_and_option() {
+        let json = command_json(&Cli::command());
+        let root = Cli::command();
+        for arg in root.get_arguments().chain(
+            root.get_subcommands()
+                .flat_map(|command| command.get_arguments()),
+        ) {
+            let name = format!("\"name\":{}", json_string(arg.get_id().as_str()));
+            assert!(json.contains(&name), "missing option {}", arg.get_id());
+        }
+        for command in root.get_subcommands() {
+            let name = format!("\"name\":{}", json_string(command.get_name()));
+            assert!(
+                json.contains(&name),
+                "missing subcommand {}",
+                command.get_name()
+            );
+        }
+        assert!(json.contains("\"type\":\"path\""));
+    }
+
+    #[test]
+    fn help_json_only_counts_in_flag_position() {
+        let requested = |line: &str, flag: &str| {
+            flag_requested(&line.split(' ').map(String::from).collect::<Vec<_>>(), flag)
+        };
+        assert!(requested("--help-json", "--help-json"));
+        assert!(requested("process a.txt --help-json", "--help-json"));
+        assert!(!requested("config --get --help-json", "--help-json"));
+        assert!(!requested(
+            "process --output --help-json a.txt",
+            "--help-json"
+        ));
+        assert!(!requested("process -b --help-json a.txt", "--help-json"));
+        assert!(!requested("process -- --help-json", "--help-json"));
+        assert!(requested("--status-json process a.txt", "--status-json"));
+        assert!(!requested(
+            "process --output --status-json a.txt",
+            "--status-json"
+        ));
+    }
+
+    #[test]
+    fn output_mode_parses_octal() {
+        assert_eq!(parse_mode("640"), Ok(0o640));
+        assert_eq!(parse_mode("0640"), Ok(0o640));
+        assert_eq!(parse_mode("0o640"), Ok(0o640));
+        assert_eq!(parse_mode("7777"), Ok(0o7777));
+        assert!(parse_mode("10000").is_err());
+        assert!(parse_mode("648").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[test]
+    fn single_star_stays_within_a_component() {
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(!glob_match("*.txt", "docs/notes.txt"));
+        assert!(glob_match("docs/*.txt", "docs/notes.txt"));
+        assert!(!glob_match("docs/*.txt", "docs/old/notes.txt"));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        assert!(glob_match("**/*.txt", "notes.txt"));
+        assert!(glob_match("**/*.txt", "docs/notes.txt"));
+        assert!(glob_match("**/*.txt", "docs/old/notes.txt"));
+        assert!(glob_match("docs/**/notes.txt", "docs/notes.txt"));
+        assert!(!glob_match("**/*.txt", "docs/notes.csv"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(glob_match("file?.log", "file1.log"));
+        assert!(!glob_match("file?.log", "file.log"));
+        assert!(!glob_match("file?.log", "file12.log"));
+        assert!(!glob_match("a?b", "a/b"));
+    }
+
+    #[test]
+    fn negated_patterns_exclude_and_last_match_wins() {
+        let patterns = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let keep_txt = patterns(&["**/*.txt", "!**/draft*"]);
+        assert!(patterns_select(&keep_txt, "docs/notes.txt"));
+        assert!(!patterns_select(&keep_txt, "docs/draft.txt"));
+        assert!(!patterns_select(&keep_txt, "image.png"));
+        let only_negations = patterns(&["!*.tmp"]);
+        assert!(patterns_select(&only_negations, "data.csv"));
+        assert!(!patterns_select(&only_negations, "scratch.tmp"));
+        let reincluded = patterns(&["!*.log", "keep.log"]);
+        assert!(patterns_select(&reincluded, "keep.log"));
+        assert!(!patterns_select(&reincluded, "other.log"));
+    }
+
+    #[test]
+    fn missing_parents_are_refused_without_parents() {
+        let dir = temp_dir("parents");
+        let nested = dir.join("out/deeply/nested/missing/dir");
+        let planned = flatten_paths(&[PathBuf::from("a.txt")], &nested).unwrap();
+        let err = check_parents(&planned).unwrap_err();
+        assert!(err.contains("missing/dir"), "{err}");
+        fs::create_dir_all(&nested).unwrap();
+        assert!(check_parents(&planned).is_ok());
+
+        let Commands::Files(args) = parse("files", &["-s", "a.txt", "-d", "out", "--mkdir"])
+            .unwrap()
+            .command
+        else {
+            panic!("expected the files subcommand");
+        };
+        assert!(args.parents);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update_compares_mtimes() {
+        let dir = temp_dir("update");
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        let stamp = |path: &Path, secs: u64| {
+            let file = File::create(path).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+        stamp(&source, 1_000_000);
+        assert!(
+            !up_to_date(&source, &target),
+            "missing destination is copied"
+        );
+        stamp(&target, 1_000_000);
+        assert!(up_to_date(&source, &target), "equal mtimes are skipped");
+        stamp(&target, 999_999);
+        assert!(!up_to_date(&source, &target), "older destination is copied");
+        stamp(&target, 1_000_001);
+        assert!(up_to_date(&source, &target), "newer destination is skipped");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flags_override_environment_which_overrides_defaults() {
+        let threads = |args: &[&str]| {
+            let cli = parse("process", &[&["a.txt"], args].concat()).unwrap();
+            match cli.command {
+                Commands::Process(args) => args.threads,
+                _ => unreachable!(),
+            }
+        };
+        env::remove_var("MYCLI_THREADS");
+        assert_eq!(threads(&[]), 1);
+        env::set_var("MYCLI_THREADS", "6");
+        assert_eq!(threads(&[]), 6);
+        assert_eq!(threads(&["--threads", "2"]), 2);
+        env::remove_var("MYCLI_THREADS");
+    }
+
+    #[test]
+    fn compress_output_accepts_gzip_and_zstd() {
+        let compression = |value: &str| {
+            let cli = parse("process", &["a.txt", "--compress-output", value])?;
+            match cli.command {
+                Commands::Process(args) => Ok::<_, clap::Error>(args.compress_output),
+                _ => unreachable!(),
+            }
+        };
+        assert!(matches!(compression("gzip"), Ok(Some(Compression::Gzip))));
+        assert!(matches!(compression("zstd"), Ok(Some(Compression::Zstd))));
+        assert!(compression("bzip2").is_err());
+    }
+
+    #[test]
+    fn dry_run_plan_is_written_without_touching_inputs() {
+        assert!(parse("process", &["a.txt", "--plan-file", "p"]).is_err());
+        assert!(parse("process", &["a.txt", "--dry-run", "--plan-file", "p"]).is_ok());
+
+        let dir = temp_dir("dry-run");
+        let input = dir.join("input.txt");
+        fs::write(&input, "unchanged").unwrap();
+        let before = plan_stamp(&input).unwrap();
+        let plan = dir.join("plan");
+        write_plan(&plan, std::slice::from_ref(&input)).unwrap();
+        assert!(plan.exists());
+        assert_eq!(plan_stamp(&input).unwrap(), before);
+        assert_eq!(fs::read_to_string(&input).unwrap(), "unchanged");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sampling_is_reproducible_and_bounded() {
+        let paths: Vec<PathBuf> = (0..200)
+            .map(|i| PathBuf::from(format!("f{i:03}")))
+            .collect();
+        for sample in [Sample::Count(17), Sample::Fraction(0.25)] {
+            let first = sample_paths(paths.clone(), &sample, 42);
+            assert_eq!(first, sample_paths(paths.clone(), &sample, 42));
+            assert_ne!(first, sample_paths(paths.clone(), &sample, 43));
+            assert!(
+                first.windows(2).all(|pair| pair[0] < pair[1]),
+                "input order is kept"
+            );
+        }
+        assert_eq!(sample_paths(paths.clone(), &Sample::Count(17), 7).len(), 17);
+        assert_eq!(sample_paths(paths.clone(), &Sample::Count(500), 7), paths);
+        assert!(sample_paths(paths.clone(), &Sample::Count(0), 7).is_empty());
+        assert_eq!(
+            sample_paths(paths.clone(), &Sample::Fraction(1.0), 7),
+            paths
+        );
+        let quarter = sample_paths(paths.clone(), &Sample::Fraction(0.25), 7).len();
+        assert!((25..=75).contains(&quarter), "got {quarter} of 200");
+        assert!(parse_sample("0").is_ok());
+        assert!(parse_sample("1.5").is_err());
+        assert!(parse_sample("0.0").is_err());
+    }
+
+    #[test]
+    fn config_keys_skip_block_scalars_and_lists() {
+        let contents = "\
+name: demo
+script: |
+  echo one: two
+  run: this
+banner: >-
+  folded: text
+
+servers:
+  - host: a
+    port: 1
+  - host: b
+tags:
+- x: 1
+  y: 2
+db:
+  port: 5432
+";
+        assert_eq!(
+            config_keys(contents),
+            ["banner", "db", "db.port", "name", "script", "servers", "tags"]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn walks_stay_within_pattern_depth_and_skip_symlinked_dirs() {
+        let dir = temp_dir("walk");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/top.txt"), "").unwrap();
+        fs::write(dir.join("a/b/deep.txt"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("a"), dir.join("a/loop")).unwrap();
+        let root = dir.to_string_lossy();
+
+        let mut files = Vec::new();
+        collect_files(&dir, u32::MAX, &mut files);
+        assert_eq!(files, [dir.join("a/b/deep.txt"), dir.join("a/top.txt")]);
+
+        assert_eq!(
+            expand_glob(&format!("{root}/*/*.txt")),
+            [dir.join("a/top.txt")]
+        );
+        assert_eq!(
+            expand_glob(&format!("{root}/**/*.txt")),
+            [dir.join("a/b/deep.txt"), dir.join("a/top.txt")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn then_runs_on_failure_unless_only_on_success() {
+        let dir = temp_dir("then");
+        let out = dir.join("out");
+        let cmd = format!("echo \"$MYCLI_EXIT\" > '{}'", out.display());
+        assert!(run_then(&cmd, false, 1));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "1\n");
+        fs::remove_file(&out).unwrap();
+        assert!(!run_then(&cmd, true, 1));
+        assert!(!out.exists());
+        assert!(run_then(&cmd, true, 0));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "0\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn set_file_splits_at_the_first_separator() {
+        let entries = parse_set_file("url: http://h/?a=b\nexpr=a: b\nempty:\n").unwrap();
+        let entries: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            [("url", "http://h/?a=b"), ("expr", "a: b"), ("empty", "")]
+        );
+    }
+
+    #[test]
+    fn civil_date_converts_days_since_the_epoch() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(11_017), (2000, 3, 1));
+        assert_eq!(civil_date(19_782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn destination_templates_render_fields_and_refuse_collisions() {
+        let dir = temp_dir("template");
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        let (a, b) = (dir.join("a/x.txt"), dir.join("b/x.txt"));
+        for path in [&a, &b] {
+            File::create(path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(19_782 * 86_400))
+                .unwrap();
+        }
+        let out = Path::new("out");
+        let planned = template_paths(
+            std::slice::from_ref(&a),
+            "{year}/{month}/{parent}-{stem}.{ext}",
+            out,
+        )
+        .unwrap();
+        assert_eq!(planned[0].1, PathBuf::from("out/2024/02/a-x.txt"));
+        let planned = template_paths(std::slice::from_ref(&a), "{date}//./{name}", out).unwrap();
+        assert_eq!(planned[0].1, PathBuf::from("out/2024-02-29/x.txt"));
+        assert!(template_paths(std::slice::from_ref(&a), "../{name}", out).is_err());
+        assert!(template_paths(&[a.clone(), b.clone()], "{ext}", out).is_err());
+        assert!(template_paths(&[a, b], "{parent}/{name}", out).is_ok());
+        assert!(parse_destination_template("{name}{newline}x").is_err());
+        assert!(parse_destination_template("{name}{tab}x").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flat_copies_refuse_shared_file_names() {
+        let sources = [PathBuf::from("a/x.txt"), PathBuf::from("b/x.txt")];
+        let err = flatten_paths(&sources, Path::new("out")).unwrap_err();
+        assert!(err.contains("a/x.txt") && err.contains("b/x.txt"), "{err}");
+        assert!(flatten_paths(&sources[..1], Path::new("out")).is_ok());
+    }
+
+    // Build one tar member: a ustar header followed by the body padded to 512 bytes
+    fn tar_entry(name: &str, kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0; 512];
+        entry[..name.len()].copy_from_slice(name.as_bytes());
+        entry[124..136].copy_from_slice(format!("{:011o}\0", body.len()).as_bytes());
+        entry[156] = kind;
+        entry[257..262].copy_from_slice(b"ustar");
+        entry.extend(body);
+        entry.resize(512 + body.len().div_ceil(512) * 512, 0);
+        entry
+    }
+
+    #[test]
+    fn tar_members_lists_regular_files_and_long_names() {
+        let long = format!("{}/b.csv", "d".repeat(120));
+        let mut data = tar_entry("a.csv", b'0', b"x,y\n");
+        data.extend(tar_entry("dir/", b'5', b""));
+        data.extend(tar_entry(
+            "././@LongLink",
+            b'L',
+            format!("{long}\0").as_bytes(),
+        ));
+        data.extend(tar_entry(&long[..100], b'0', b"1,2\n"));
+        data.extend([0; 1024]);
+        assert_eq!(tar_members(&data).unwrap(), ["a.csv".to_string(), long]);
+        let truncated = tar_entry("big.csv", b'0', &[b'z'; 600]);
+        assert!(tar_members(&truncated[..512 + 100]).is_err());
+    }
+
+    #[test]
+    fn zip_members_reads_the_central_directory() {
+        let mut data = b"local file data".to_vec();
+        let directory = data.len() as u32;
+        for name in ["a.csv", "dir/", "dir/b.csv"] {
+            let mut entry = b"PK\x01\x02".to_vec();
+            entry.resize(46, 0);
+            entry[28..30].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            entry.extend(name.as_bytes());
+            data.extend(entry);
+        }
+        let mut end = b"PK\x05\x06".to_vec();
+        end.resize(22, 0);
+        end[10..12].copy_from_slice(&3u16.to_le_bytes());
+        end[16..20].copy_from_slice(&directory.to_le_bytes());
+        data.extend(end);
+        assert_eq!(zip_members(&data).unwrap(), ["a.csv", "dir/b.csv"]);
+        assert!(zip_members(b"not a zip").is_err());
+    }
+
+    #[test]
+    fn recursive_patterns_keep_archives_for_expansion() {
+        let dir = temp_dir("archives");
+        fs::write(dir.join("x.tar"), "").unwrap();
+        fs::write(dir.join("y.txt"), "").unwrap();
+        let depth = MaxDepth {
+            default: 10,
+            sources: Vec::new(),
+        };
+        let patterns = ["*.csv".to_string()];
+        assert_eq!(
+            expand_inputs(vec![dir.clone()], &depth, &patterns, true),
+            [dir.join("x.tar")]
+        );
+        assert!(expand_inputs(vec![dir.clone()], &depth, &patterns, false).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn non_empty_destinations_need_merge_or_force() {
+        let dir = temp_dir("destination");
+        assert!(check_destination(&dir, false).is_ok(), "empty destination");
+        assert!(check_destination(&dir.join("missing"), false).is_ok());
+        fs::write(dir.join("existing.txt"), "").unwrap();
+        assert!(check_destination(&dir, false).is_err());
+        assert!(check_destination(&dir, true).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_lines_follow_the_log_format() {
+        assert_eq!(
+            log_line("warning", "skipping \"m\"", false),
+            "Warning: skipping \"m\""
+        );
+        assert_eq!(
+            log_line("warning", "skipping \"m\"", true),
+            r#"{"level":"warning","message":"skipping \"m\""}"#
+        );
+    }
+
+    #[test]
+    fn plans_refuse_stdin() {
+        let dir = temp_dir("stdin");
+        let plan = dir.join("plan");
+        let err = write_plan(&plan, &[PathBuf::from("-")]).unwrap_err();
+        assert!(err.contains("stdin"));
+        assert!(!plan.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn base_requires_a_mirroring_mode() {
+        assert!(parse("files", &["-s", "a", "--base", "d"]).is_err());
+        assert!(parse(
+            "files",
+            &[
+                "-s",
+                "a",
+                "-d",
+                "out",
+                "--preserve-structure",
+                "--base",
+                "d"
+            ]
+        )
+        .is_ok());
+        assert!(parse("files", &["--stdin0", "-d", "out", "--base", "d"]).is_ok());
+    }
+
+    #[test]
+    fn env_file_rejects_invalid_keys() {
+        assert!(parse_env_file("=oops").is_err());
+        assert!(parse_env_file("MYCLI_A\0B=1").is_err());
+        assert!(parse_env_file("MYCLI_A=x\0y").is_err());
+        assert_eq!(
+            parse_env_file("export MYCLI_A='a b'\n").unwrap(),
+            vec![("MYCLI_A".to_string(), "a b".to_string())]
+        );
+        assert_eq!(
+            parse_env_file("MYCLI_A=\"a # b\" # note\n").unwrap(),
+            vec![("MYCLI_A".to_string(), "a # b".to_string())]
+        );
+        assert!(parse_env_file("MYCLI_A=\"a\" b").is_err());
+        assert!(parse_env_file("MYCLI_A=\"a").is_err());
+    }
+}